@@ -0,0 +1,137 @@
+//! Alert sounds, synthesized as make_alert_sound.m does or read from
+//! files
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{
+	Command,
+	Stdio
+    }
+};
+use anyhow::{
+    anyhow,
+    Result
+};
+use tracing::warn;
+use discipline_net::{
+    Alert,
+    Beep,
+    Sound
+};
+
+/// Index, starting at 1, of the last alert whose threshold is not
+/// below the time remaining, or 0 if there is none; as
+/// find_alert_index in the session script.  The alerts are sorted by
+/// decreasing threshold.
+pub fn index(alerts:&[Alert],t:f64)->usize {
+    alerts.iter()
+	.rposition(|a| t <= a.before)
+	.map(|i| i + 1)
+	.unwrap_or(0)
+}
+
+/// Spoken message of an alert, if it has one in the language
+pub fn speech(alert:&Alert,language:&str,name:&str,t:f64)->Option<String> {
+    let template = alert.speech.get(language)?;
+    let minutes = (t/60.0).ceil().max(0.0) as u64;
+    Some(template.replace("{name}",name)
+	 .replace("{minutes}",&minutes.to_string()))
+}
+
+/// Goes from 0 to 1 around x0, reaching 1 - epsilon at x0 + dx
+fn smooth_edge(x:f64,x0:f64,dx:f64,epsilon:f64)->f64 {
+    (((x - x0)*(1.0 - epsilon).atanh()/dx).tanh() + 1.0)/2.0
+}
+
+pub fn synthesize(beep:&Beep)->Result<Vec<i16>> {
+    beep.check().map_err(|e| anyhow!(e))?;
+    let fs = Beep::SAMPLE_RATE as f64;
+    let epsilon = 0.01;
+    let m = ((beep.on + beep.off)*fs).ceil() as usize;
+    let one : Vec<i16> = (0..m)
+	.map(|i| {
+	    let t = i as f64/fs;
+	    let env = smooth_edge(t,beep.edge,beep.edge,epsilon)
+		* (1.0 - smooth_edge(t,beep.on - beep.edge,beep.edge,epsilon));
+	    let tone = 0.5*(beep.a1*(2.0*std::f64::consts::PI*beep.f1*t).cos()
+			    + beep.a2*(2.0*std::f64::consts::PI*beep.f2*t).cos());
+	    (env*tone*i16::MAX as f64).round() as i16
+	})
+	.collect();
+    Ok(one.repeat(beep.count as usize))
+}
+
+/// Mono 16-bit PCM WAV file
+pub fn wav(samples:&[i16])->Vec<u8> {
+    let data_len = 2*samples.len() as u32;
+    let mut u = Vec::with_capacity(44 + data_len as usize);
+    u.extend_from_slice(b"RIFF");
+    u.extend_from_slice(&(36 + data_len).to_le_bytes());
+    u.extend_from_slice(b"WAVEfmt ");
+    u.extend_from_slice(&16u32.to_le_bytes());
+    u.extend_from_slice(&1u16.to_le_bytes());
+    u.extend_from_slice(&1u16.to_le_bytes());
+    u.extend_from_slice(&Beep::SAMPLE_RATE.to_le_bytes());
+    u.extend_from_slice(&(2*Beep::SAMPLE_RATE).to_le_bytes());
+    u.extend_from_slice(&2u16.to_le_bytes());
+    u.extend_from_slice(&16u16.to_le_bytes());
+    u.extend_from_slice(b"data");
+    u.extend_from_slice(&data_len.to_le_bytes());
+    for x in samples {
+	u.extend_from_slice(&x.to_le_bytes());
+    }
+    u
+}
+
+/// Command running a player in the audio session of the user
+fn player(user:&str,uid:u32,args:&[&str])->Command {
+    let mut cmd = Command::new("runuser");
+    cmd.args(["-u",user,"--","env"])
+	.arg(format!("XDG_RUNTIME_DIR=/run/user/{}",uid))
+	.args(args);
+    cmd
+}
+
+/// Play a sound, then speak the message with the voice if any,
+/// without waiting for them to end
+pub fn play(user:&str,uid:u32,sound:&Sound,speech:Option<(String,String)>)
+	    ->Result<()> {
+    let (mut child,input) =
+	match sound {
+	    Sound::Beep(beep) => {
+		let input = wav(&synthesize(beep)?);
+		let child = player(user,uid,&["aplay","-q","-"])
+		    .stdin(Stdio::piped())
+		    .spawn()?;
+		(child,Some(input))
+	    },
+	    Sound::File(path) => {
+		let ogg = Path::new(path).extension()
+		    .map(|e| e.eq_ignore_ascii_case("ogg"))
+		    .unwrap_or(false);
+		let args : &[&str] = if ogg { &["ogg123","-q"] } else { &["aplay","-q"] };
+		let child = player(user,uid,args).arg(path).spawn()?;
+		(child,None)
+	    }
+	};
+    let stdin = child.stdin.take();
+    let user = user.to_string();
+    std::thread::spawn(move || {
+	if let (Some(mut stdin),Some(input)) = (stdin,input) {
+	    if let Err(e) = stdin.write_all(&input) {
+		warn!(error = %e,"Cannot feed the player");
+	    }
+	}
+	if let Err(e) = child.wait() {
+	    warn!(error = %e,"Cannot wait for the player");
+	}
+	if let Some((voice,text)) = speech {
+	    if let Err(e) = player(&user,uid,&["espeak-ng","-v",&voice,&text])
+		.status() {
+		    warn!(error = %e,"Cannot speak");
+		}
+	}
+    });
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::{
+	BTreeMap,
+	BTreeSet
+    },
+    time::Instant
+};
+use anyhow::Result;
+use tracing::{
+    info,
+    warn
+};
+use discipline_net::{
+    AppBudget,
+    AppUsage
+};
+use crate::procs::{
+    self,
+    Process
+};
+
+/// Application usage of one user
+pub struct Apps {
+    uid:u32,
+    interval:f64,
+    /// Budgets as of the last report
+    budgets:Vec<AppBudget>,
+    /// Usage not yet reported to the server
+    pending:BTreeMap<String,f64>,
+    /// Processes already asked to terminate
+    terminated:BTreeSet<i32>,
+    last_scan:Option<Instant>
+}
+
+impl Apps {
+    /// Longest gap between two scans that is counted as usage, in
+    /// intervals, so that a suspended machine is not charged
+    const MAX_GAP : f64 = 3.0;
+
+    pub fn new(uid:u32,interval:f64)->Self {
+	Self {
+	    uid,
+	    interval,
+	    budgets:Vec::new(),
+	    pending:BTreeMap::new(),
+	    terminated:BTreeSet::new(),
+	    last_scan:None
+	}
+    }
+
+    fn category(&self,p:&Process)->Option<&AppBudget> {
+	self.budgets.iter()
+	    .find(|b| b.category.matches(&p.comm,p.exe.as_deref()))
+    }
+
+    /// Charge the time since the last scan to the categories with
+    /// running processes
+    pub fn scan(&mut self)->Result<Vec<Process>> {
+	let procs = procs::scan(self.uid)?;
+	let t_now = Instant::now();
+	let dt = self.last_scan
+	    .map(|t| (t_now - t).as_secs_f64())
+	    .unwrap_or(0.0);
+	self.last_scan = Some(t_now);
+	if dt > Self::MAX_GAP * self.interval {
+	    warn!(uid = self.uid,gap = dt,"Not counting usage across a long gap");
+	    return Ok(procs);
+	}
+	let running : BTreeSet<String> = procs.iter()
+	    .filter_map(|p| self.category(p))
+	    .map(|b| b.category.name.clone())
+	    .collect();
+	for name in running {
+	    *self.pending.entry(name).or_insert(0.0) += dt;
+	}
+	Ok(procs)
+    }
+
+    pub fn usage(&self)->Vec<AppUsage> {
+	self.pending.iter()
+	    .map(|(category,&seconds)| AppUsage { category:category.clone(),
+						  seconds })
+	    .collect()
+    }
+
+    /// Record the answer of the server to the usage report
+    pub fn reported(&mut self,budgets:Vec<AppBudget>) {
+	self.pending.clear();
+	self.budgets = budgets;
+    }
+
+    /// Terminate the processes of exhausted categories, killing
+    /// those that survived a previous request
+    pub fn enforce(&mut self,procs:&[Process],dry_run:bool) {
+	let mut terminated = BTreeSet::new();
+	for p in procs {
+	    let Some(b) = self.category(p) else { continue };
+	    if !b.exhausted() {
+		continue;
+	    }
+	    let category = &b.category.name;
+	    if dry_run {
+		info!(pid = p.pid,name = p.comm,category,
+		      "Would be terminating");
+		continue;
+	    }
+	    let signal =
+		if self.terminated.contains(&p.pid) {
+		    libc::SIGKILL
+		} else {
+		    libc::SIGTERM
+		};
+	    info!(pid = p.pid,name = p.comm,category,signal,
+		  "Out of time, terminating");
+	    match procs::kill(p.pid,signal) {
+		Ok(()) => { terminated.insert(p.pid); },
+		Err(e) => warn!(error = %e,"Cannot terminate")
+	    }
+	}
+	self.terminated = terminated;
+    }
+}
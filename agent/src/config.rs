@@ -0,0 +1,148 @@
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path
+};
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+use url::Url;
+use discipline_net::{
+    Alert,
+    MAX_DURATION
+};
+
+/// Local Unix user and the subject it is accounted to
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Kid {
+    pub user:String,
+    /// Same as the user name if not given
+    #[serde(default)]
+    pub subject:Option<String>,
+    /// Name used in spoken messages, the capitalized subject if not
+    /// given
+    #[serde(default)]
+    pub name:Option<String>,
+    /// Language of spoken messages, overriding that of the agent
+    #[serde(default)]
+    pub language:Option<String>
+}
+
+impl Kid {
+    pub fn subject(&self)->&str {
+	self.subject.as_deref().unwrap_or(&self.user)
+    }
+
+    pub fn name(&self)->String {
+	match &self.name {
+	    Some(name) => name.clone(),
+	    None => {
+		let mut chars = self.subject().chars();
+		chars.next()
+		    .map(|c| c.to_uppercase().chain(chars).collect())
+		    .unwrap_or_default()
+	    }
+	}
+    }
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "Config::default_url")]
+    pub url:String,
+    /// Name of the agent on the server, the host name if not given
+    #[serde(default)]
+    pub name:Option<String>,
+    pub kids:Vec<Kid>,
+    /// Interval between two checks
+    #[serde(default = "Config::default_loop_delay")]
+    pub loop_delay:f64,
+    #[serde(default = "Config::default_retry_delay")]
+    pub retry_delay:f64,
+    /// Interval between two fetches of the alerts from the server
+    #[serde(default = "Config::default_alerts_refresh")]
+    pub alerts_refresh:f64,
+    /// Alerts of the kids the server has none for, or of all of them
+    /// with a server that does not give alerts
+    #[serde(default)]
+    pub alerts:Vec<Alert>,
+    /// Speak the messages of the alerts with espeak-ng
+    #[serde(default)]
+    pub speak:bool,
+    /// Default language of spoken messages, also the espeak-ng voice
+    #[serde(default = "Config::default_language")]
+    pub language:String
+}
+
+impl Config {
+    fn default_url()->String {
+	"ws://127.0.0.1:9001".to_string()
+    }
+
+    fn default_loop_delay()->f64 {
+	5.0
+    }
+
+    fn default_retry_delay()->f64 {
+	10.0
+    }
+
+    fn default_alerts_refresh()->f64 {
+	300.0
+    }
+
+    fn default_language()->String {
+	"en".to_string()
+    }
+
+    pub fn name(&self)->Result<String> {
+	match &self.name {
+	    Some(name) => Ok(name.clone()),
+	    None => Ok(std::fs::read_to_string("/proc/sys/kernel/hostname")?
+		       .trim().to_string())
+	}
+    }
+
+    pub fn load<P:AsRef<Path>>(path:P)->Result<Self> {
+	let path = path.as_ref();
+	let fd = File::open(path)
+	    .map_err(|e| anyhow!("Cannot open configuration file {:?}: {}",
+				 path,e))?;
+	let buf = BufReader::new(fd);
+	let this : Self = ron::de::from_reader(buf)
+	    .map_err(|e| anyhow!("Cannot parse configuration file {:?}: {}",
+				 path,e))?;
+	this.validate()
+	    .map_err(|e| anyhow!("Invalid configuration file {:?}: {}",
+				 path,e))?;
+	Ok(this)
+    }
+
+    pub fn validate(&self)->Result<()> {
+	Url::parse(&self.url)
+	    .map_err(|e| anyhow!("Invalid URL {:?}: {}",self.url,e))?;
+	if self.kids.is_empty() {
+	    bail!("No kids");
+	}
+	for (i,kid) in self.kids.iter().enumerate() {
+	    if self.kids[..i].iter().any(|k| k.user == kid.user) {
+		bail!("User {:?} appears twice",kid.user);
+	    }
+	}
+	for t in [self.loop_delay,self.retry_delay,self.alerts_refresh] {
+	    if !(t > 0.0 && t <= MAX_DURATION) {
+		bail!("Invalid delay {}",t);
+	    }
+	}
+	for alert in self.alerts.iter() {
+	    alert.check()
+		.map_err(|e| anyhow!("Invalid alert at {}: {}",alert.before,e))?;
+	}
+	Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+mod alerts;
+mod apps;
+mod config;
+mod network;
+mod procs;
+mod sessions;
+
+use std::{
+    net::TcpStream,
+    time::{
+	Duration,
+	Instant
+    }
+};
+use url::Url;
+use tungstenite::{
+    connect,
+    stream::MaybeTlsStream,
+    Message,
+    WebSocket
+};
+use pico_args::Arguments;
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+use tracing::{
+    debug,
+    error,
+    info,
+    warn
+};
+use discipline_net::{
+    *,
+    logging::{
+	LogFormat,
+	LogLevel,
+	LOG_USAGE
+    }
+};
+use apps::Apps;
+use config::{
+    Config,
+    Kid
+};
+use sessions::Session;
+
+struct Connection {
+    socket:WebSocket<MaybeTlsStream<TcpStream>>,
+    sender:Entity,
+    encoding:Encoding,
+    features:Vec<Feature>
+}
+
+impl Connection {
+    fn open(url:&Url,sender:Entity)->Result<Self> {
+	let (socket,_response) = connect(url.clone())?;
+	debug!(url = %url,"Connected");
+	let mut conn = Self { socket,sender,encoding:Encoding::Json,
+			      features:Vec::new() };
+	let hello = Command::Hello { version:PROTOCOL_VERSION,
+				     features:Feature::ALL.to_vec() };
+	conn.features =
+	    match conn.transact(hello)? {
+		Response::Welcome { features,.. } => features,
+		_ => bail!("Unexpected response")
+	    };
+	if !conn.features.contains(&Feature::Agents) {
+	    bail!("The server does not support agents");
+	}
+	if conn.features.contains(&Feature::Cbor) {
+	    conn.encoding = Encoding::Cbor;
+	}
+	Ok(conn)
+    }
+
+    fn transact(&mut self,payload:Command)->Result<Response> {
+	let cmd = Envelope {
+	    sender:self.sender.clone(),
+	    signature:"\\_'')_/".to_string(),
+	    payload
+	};
+	let msg =
+	    match self.encoding {
+		Encoding::Json => Message::Text(serde_json::to_string(&cmd)?),
+		Encoding::Cbor => Message::Binary(
+		    Encoding::Cbor.encode(&cmd).map_err(|e| anyhow!(e))?)
+	    };
+	self.socket.send(msg)?;
+	let env =
+	    match self.socket.read()? {
+		Message::Text(u) => decode_response(&u)?,
+		Message::Binary(u) => Encoding::Cbor.decode_response(&u)?,
+		_ => bail!("Invalid message type")
+	    };
+	Ok(env.payload.into_result()?)
+    }
+}
+
+/// Whether the server refused a request, which retrying will not fix
+fn refused(e:&anyhow::Error)->bool {
+    e.downcast_ref::<RemoteError>()
+	.is_some_and(|e| !e.code.is_transient())
+}
+
+/// What the agent knows of a kid
+struct KidState {
+    kid:Kid,
+    uid:u32,
+    apps:Apps,
+    /// Alerts of the subject, by decreasing threshold
+    alerts:Vec<Alert>,
+    alert_index:usize
+}
+
+struct Agent {
+    config:Config,
+    url:Url,
+    name:String,
+    dry_run:bool,
+    kids:Vec<KidState>
+}
+
+impl Agent {
+    fn kick(&self,ks:&KidState,sessions:&[&Session]) {
+	for session in sessions {
+	    if self.dry_run {
+		info!(user = ks.kid.user,session = session.id,
+		      "Would be kicking");
+		continue;
+	    }
+	    info!(user = ks.kid.user,session = session.id,"Kicking");
+	    if let Err(e) = sessions::terminate(session) {
+		error!(user = ks.kid.user,error = %e,"Cannot kick");
+	    }
+	}
+    }
+
+    fn block(&self,ks:&KidState) {
+	let subject = ks.kid.subject();
+	if network::blocked(subject) {
+	    return;
+	}
+	if self.dry_run {
+	    info!(user = ks.kid.user,"Would be blocking network access");
+	    return;
+	}
+	info!(user = ks.kid.user,"Blocking network access");
+	if let Err(e) = network::block(subject,ks.uid) {
+	    error!(user = ks.kid.user,error = %e,"Cannot block network access");
+	}
+    }
+
+    fn unblock(&self,ks:&KidState) {
+	let subject = ks.kid.subject();
+	if !network::blocked(subject) {
+	    return;
+	}
+	if self.dry_run {
+	    info!(user = ks.kid.user,"Would be restoring network access");
+	    return;
+	}
+	info!(user = ks.kid.user,"Restoring network access");
+	if let Err(e) = network::unblock(subject) {
+	    error!(user = ks.kid.user,error = %e,"Cannot restore network access");
+	}
+    }
+
+    fn alert(&self,ks:&KidState,index:usize,time_remaining:f64) {
+	let alert = &ks.alerts[index - 1];
+	let language = ks.kid.language.as_ref().unwrap_or(&self.config.language);
+	let speech =
+	    if self.config.speak {
+		alerts::speech(alert,language,&ks.kid.name(),time_remaining)
+	    } else {
+		None
+	    };
+	if self.dry_run {
+	    info!(user = ks.kid.user,index,sound = ?alert.sound,speech,
+		  "Would be sounding alert");
+	    return;
+	}
+	info!(user = ks.kid.user,index,speech,"Alert");
+	let speech = speech.map(|text| (language.clone(),text));
+	if let Err(e) = alerts::play(&ks.kid.user,ks.uid,&alert.sound,speech) {
+	    error!(user = ks.kid.user,error = %e,"Cannot sound alert");
+	}
+    }
+
+    fn enforce(&mut self,i:usize,status:&Status,sessions:&[&Session]) {
+	let ks = &self.kids[i];
+	let mut alert_index = ks.alert_index;
+	if status.time_remaining <= 0.0 {
+	    match status.enforcement {
+		Enforcement::Network => self.block(ks),
+		Enforcement::Logout => {
+		    self.unblock(ks);
+		    self.kick(ks,sessions);
+		}
+	    }
+	    alert_index = 0;
+	} else {
+	    self.unblock(ks);
+	    let index = alerts::index(&ks.alerts,status.time_remaining);
+	    if index > alert_index {
+		self.alert(ks,index,status.time_remaining);
+	    }
+	    alert_index = index;
+	}
+	self.kids[i].alert_index = alert_index;
+    }
+
+    fn report_usage(&mut self,conn:&mut Connection,i:usize)->Result<()> {
+	let ks = &mut self.kids[i];
+	let procs = ks.apps.scan()?;
+	let cmd = Command::ReportUsage { subject:ks.kid.subject().to_string(),
+					 usage:ks.apps.usage() };
+	match conn.transact(cmd) {
+	    Ok(Response::AppBudgets { budgets,.. }) => ks.apps.reported(budgets),
+	    Ok(_) => bail!("Unexpected response"),
+	    Err(e) if refused(&e) => {
+		warn!(subject = ks.kid.subject(),error = %e,"Usage refused");
+	    },
+	    Err(e) => return Err(e)
+	}
+	ks.apps.enforce(&procs,self.dry_run);
+	Ok(())
+    }
+
+    /// Query the kids with a session in one request, then enforce
+    fn tick(&mut self,conn:&mut Connection)->Result<()> {
+	let sessions = sessions::list()?;
+	let active : Vec<usize> = (0..self.kids.len())
+	    .filter(|&i| sessions.iter().any(|s| s.user == self.kids[i].kid.user))
+	    .collect();
+	for (i,ks) in self.kids.iter_mut().enumerate() {
+	    if !active.contains(&i) {
+		ks.alert_index = 0;
+	    }
+	}
+	// Sent even when nobody is logged in, as it also tells the
+	// server that the agent is alive
+	let subjects : Vec<String> = active.iter()
+	    .map(|&i| self.kids[i].kid.subject().to_string())
+	    .collect();
+	let statuses = Self::statuses(conn,subjects)?;
+	for status in statuses.iter() {
+	    let Some(&i) = active.iter()
+		.find(|&&i| self.kids[i].kid.subject() == status.subject) else {
+		    warn!(subject = status.subject,"Unexpected status");
+		    continue
+		};
+	    let kid_sessions : Vec<&Session> = sessions.iter()
+		.filter(|s| s.user == self.kids[i].kid.user)
+		.collect();
+	    debug!(subject = status.subject,
+		   time_remaining = status.time_remaining,
+		   sessions = kid_sessions.len(),"Status");
+	    self.enforce(i,status,&kid_sessions);
+	}
+	if conn.features.contains(&Feature::AppBudgets) {
+	    for &i in active.iter() {
+		self.report_usage(conn,i)?;
+	    }
+	}
+	Ok(())
+    }
+
+    fn get_statuses(conn:&mut Connection,subjects:Vec<String>)
+		    ->Result<Vec<Status>> {
+	match conn.transact(Command::GetStatuses { subjects })? {
+	    Response::Statuses(statuses) => Ok(statuses),
+	    _ => bail!("Unexpected response")
+	}
+    }
+
+    /// Query the statuses of the subjects in one request or, if the
+    /// server refuses it, of each on its own, so that a misconfigured
+    /// kid does not leave the others unwatched
+    fn statuses(conn:&mut Connection,subjects:Vec<String>)
+		->Result<Vec<Status>> {
+	match Self::get_statuses(conn,subjects.clone()) {
+	    Err(e) if refused(&e) && subjects.len() > 1 => {
+		let mut statuses = Vec::new();
+		for subject in subjects {
+		    match Self::get_statuses(conn,vec![subject.clone()]) {
+			Ok(mut s) => statuses.append(&mut s),
+			Err(e) if refused(&e) => {
+			    warn!(subject,error = %e,"Status refused");
+			},
+			Err(e) => return Err(e)
+		    }
+		}
+		Ok(statuses)
+	    },
+	    res => res
+	}
+    }
+
+    /// Fetch the alerts of every kid, falling back to those of the
+    /// configuration for the kids the server has none for
+    fn fetch_alerts(&mut self,conn:&mut Connection)->Result<()> {
+	for ks in self.kids.iter_mut() {
+	    let subject = ks.kid.subject().to_string();
+	    match conn.transact(Command::GetAlerts { subject:subject.clone() }) {
+		Ok(Response::Alerts { mut alerts,.. }) => {
+		    if alerts.is_empty() {
+			alerts = self.config.alerts.clone();
+		    }
+		    alerts.sort_by(|a,b| b.before.total_cmp(&a.before));
+		    debug!(user = ks.kid.user,alerts = alerts.len(),"Alerts");
+		    ks.alerts = alerts;
+		},
+		Ok(_) => bail!("Unexpected response"),
+		// Keeps the alerts it has
+		Err(e) if refused(&e) => {
+		    warn!(subject,error = %e,"Alerts refused");
+		},
+		Err(e) => return Err(e)
+	    }
+	}
+	Ok(())
+    }
+
+    fn run(&mut self)->Result<()> {
+	let mut conn = Connection::open(&self.url,
+					Entity::Agent(self.name.clone()))?;
+	let mut alerts_fetched : Option<Instant> = None;
+	loop {
+	    // Refetched now and then rather than only on connection, so
+	    // that changes of the alerts reach a long-running agent
+	    if conn.features.contains(&Feature::Alerts) &&
+		alerts_fetched.is_none_or(|t| t.elapsed().as_secs_f64()
+					  >= self.config.alerts_refresh) {
+		self.fetch_alerts(&mut conn)?;
+		alerts_fetched = Some(Instant::now());
+	    }
+	    self.tick(&mut conn)?;
+	    std::thread::sleep(Duration::from_secs_f64(self.config.loop_delay));
+	}
+    }
+}
+
+fn main()->Result<()> {
+    let progname : String = std::env::args().next().unwrap();
+
+    let mut args = Arguments::from_env();
+
+    if args.contains("-h") || args.contains("--help") {
+	eprintln!("Usage: {} [--config PATH] [--url ws://ADDR:PORT/] \
+		   [--dry-run] {}",
+		  progname,LOG_USAGE);
+	return Ok(())
+    }
+
+    let config_path : String = args.opt_value_from_str("--config")?
+	.unwrap_or_else(|| "/usr/local/etc/discipline/agent.cfg".to_string());
+    let url : Option<String> = args.opt_value_from_str("--url")?;
+    let dry_run = args.contains("--dry-run");
+
+    let log_level : LogLevel = args.opt_value_from_str("--log-level")?
+	.unwrap_or(LogLevel::INFO);
+    let log_format : LogFormat = args.opt_value_from_str("--log-format")?
+	.unwrap_or(LogFormat::Text);
+
+    let rest = args.finish();
+    if !rest.is_empty() {
+	bail!("Invalid arguments: {:?}",rest);
+    }
+
+    let mut config = Config::load(&config_path)?;
+    if let Some(url) = url {
+	config.url = url;
+    }
+    let url = Url::parse(&config.url)?;
+    let name = config.name()?;
+
+    discipline_net::logging::init(log_level,log_format)?;
+
+    let mut default_alerts = config.alerts.clone();
+    default_alerts.sort_by(|a,b| b.before.total_cmp(&a.before));
+    let mut kids = Vec::new();
+    for kid in config.kids.iter() {
+	let uid = procs::uid(&kid.user)?;
+	info!(user = kid.user,uid,subject = kid.subject(),"Watching");
+	kids.push(KidState {
+	    kid:kid.clone(),
+	    uid,
+	    apps:Apps::new(uid,config.loop_delay),
+	    alerts:default_alerts.clone(),
+	    alert_index:0
+	});
+    }
+
+    let mut agent = Agent { config,url,name,dry_run,kids };
+
+    loop {
+	// Even a refusal of the server is retried rather than fatal, as
+	// an agent that exits enforces nothing until it is restarted
+	if let Err(e) = agent.run() {
+	    error!(agent = agent.name,error = %e,"Agent failed");
+	}
+	let delay = agent.config.retry_delay;
+	info!(delay,"Waiting for retry");
+	std::thread::sleep(Duration::from_secs_f64(delay));
+    }
+}
@@ -0,0 +1,124 @@
+//! Blocking the network access of a user with nftables, as the
+//! session script does
+
+use std::{
+    io::Write,
+    process::{
+	Command,
+	Stdio
+    }
+};
+use anyhow::{
+    bail,
+    Result
+};
+
+/// Same table as the session script
+fn table(subject:&str)->String {
+    format!("discipline_{}",subject)
+}
+
+pub fn blocked(subject:&str)->bool {
+    Command::new("nft")
+	.args(["list","table","inet",&table(subject)])
+	.stdout(Stdio::null())
+	.stderr(Stdio::null())
+	.status()
+	.map(|s| s.success())
+	.unwrap_or(false)
+}
+
+/// Reject outbound traffic from the user, except on the loopback
+/// interface so that local services keep working
+pub fn block(subject:&str,uid:u32)->Result<()> {
+    let rules = format!("table inet {} {{\n\
+			 \tchain output {{\n\
+			 \t\ttype filter hook output priority 0; policy accept;\n\
+			 \t\toifname \"lo\" accept\n\
+			 \t\tmeta skuid {} reject\n\
+			 \t}}\n\
+			 }}\n",table(subject),uid);
+    let mut child = Command::new("nft")
+	.args(["-f","-"])
+	.stdin(Stdio::piped())
+	.spawn()?;
+    child.stdin.take().expect("No standard input").write_all(rules.as_bytes())?;
+    if !child.wait()?.success() {
+	bail!("Cannot install the rules");
+    }
+    Ok(())
+}
+
+pub fn unblock(subject:&str)->Result<()> {
+    let status = Command::new("nft")
+	.args(["delete","table","inet",&table(subject)])
+	.status()?;
+    if !status.success() {
+	bail!("Cannot delete the rules");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+	net::TcpListener,
+	os::unix::process::CommandExt
+    };
+
+    const TIMED_OUT : Option<i32> = Some(124);
+
+    fn run(args:&[&str]) {
+	let status = Command::new(args[0]).args(&args[1..]).status().unwrap();
+	assert!(status.success(),"{:?} failed",args);
+    }
+
+    /// Exit status of a TCP connection attempt by the user, within two
+    /// seconds
+    fn connect(uid:u32,addr:&str)->Option<i32> {
+	Command::new("timeout")
+	    .args(["2","bash","-c",
+		   &format!("exec 3<>/dev/tcp/{}",addr.replace(':',"/"))])
+	    .uid(uid)
+	    .stderr(Stdio::null())
+	    .status()
+	    .unwrap()
+	    .code()
+    }
+
+    #[test]
+    #[ignore = "needs root, ip and nft; run with --ignored"]
+    fn block_in_network_namespace() {
+	// This thread, and the commands it runs, get a network
+	// namespace of their own with a veth pair on which nobody
+	// answers, so that connections through it time out
+	assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) },0,
+		   "Cannot unshare: {}",std::io::Error::last_os_error());
+	run(&["ip","link","set","lo","up"]);
+	run(&["ip","link","add","v0","type","veth","peer","name","v1"]);
+	run(&["ip","addr","add","10.99.0.1/24","dev","v0"]);
+	run(&["ip","link","set","v0","up"]);
+	run(&["ip","link","set","v1","up"]);
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let local = listener.local_addr().unwrap().to_string();
+	let remote = "10.99.0.2:80";
+	let kid = 65534;
+
+	assert!(!blocked("test"));
+	assert_eq!(connect(kid,remote),TIMED_OUT);
+	assert_eq!(connect(kid,&local),Some(0));
+
+	block("test",kid).unwrap();
+	assert!(blocked("test"));
+	let status = connect(kid,remote);
+	assert!(status != TIMED_OUT && status != Some(0),
+		"Traffic of the kid not rejected: {:?}",status);
+	assert_eq!(connect(kid,&local),Some(0),"Loopback blocked");
+	assert_eq!(connect(0,remote),TIMED_OUT,"Traffic of root rejected");
+
+	unblock("test").unwrap();
+	assert!(!blocked("test"));
+	assert_eq!(connect(kid,remote),TIMED_OUT);
+    }
+}
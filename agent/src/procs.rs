@@ -0,0 +1,76 @@
+use std::{
+    fs,
+    path::Path
+};
+use anyhow::{
+    anyhow,
+    Result
+};
+
+#[derive(Debug,Clone)]
+pub struct Process {
+    pub pid:i32,
+    /// Short name from /proc/PID/comm
+    pub comm:String,
+    /// File name of the executable, if readable
+    pub exe:Option<String>
+}
+
+fn uid_of(status:&str)->Option<u32> {
+    status.lines()
+	.find_map(|l| l.strip_prefix("Uid:"))
+	.and_then(|u| u.split_whitespace().next())
+	.and_then(|u| u.parse().ok())
+}
+
+fn read_process(dir:&Path,pid:i32,uid:u32)->Option<Process> {
+    let status = fs::read_to_string(dir.join("status")).ok()?;
+    if uid_of(&status)? != uid {
+	return None;
+    }
+    let comm = fs::read_to_string(dir.join("comm")).ok()?
+	.trim_end().to_string();
+    let exe = fs::read_link(dir.join("exe")).ok()
+	.and_then(|p| p.file_name().map(|u| u.to_string_lossy().into_owned()));
+    Some(Process { pid,comm,exe })
+}
+
+/// Processes whose real user is uid
+pub fn scan(uid:u32)->Result<Vec<Process>> {
+    let mut procs = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+	let entry = entry?;
+	let Some(pid) = entry.file_name().to_str()
+	    .and_then(|u| u.parse::<i32>().ok()) else {
+		continue
+	    };
+	// Processes may exit while being read
+	if let Some(p) = read_process(&entry.path(),pid,uid) {
+	    procs.push(p);
+	}
+    }
+    Ok(procs)
+}
+
+pub fn kill(pid:i32,signal:i32)->Result<()> {
+    if unsafe { libc::kill(pid,signal) } < 0 {
+	Err(anyhow!("Cannot signal process {}: {}",pid,
+		    std::io::Error::last_os_error()))
+    } else {
+	Ok(())
+    }
+}
+
+/// Numeric user ID of a user name or number
+pub fn uid(user:&str)->Result<u32> {
+    if let Ok(uid) = user.parse() {
+	return Ok(uid);
+    }
+    let name = std::ffi::CString::new(user)?;
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+	Err(anyhow!("Unknown user {:?}",user))
+    } else {
+	Ok(unsafe { (*pw).pw_uid })
+    }
+}
@@ -0,0 +1,40 @@
+use std::process::Command;
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+
+#[derive(Debug,Clone)]
+pub struct Session {
+    pub id:String,
+    pub user:String
+}
+
+/// Sessions known to logind
+pub fn list()->Result<Vec<Session>> {
+    let output = Command::new("loginctl")
+	.args(["list-sessions","-o","json"])
+	.output()
+	.map_err(|e| anyhow!("Cannot run loginctl: {}",e))?;
+    if !output.status.success() {
+	bail!("loginctl failed: {}",String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let sessions : Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    Ok(sessions.iter()
+       .filter_map(|s| Some(Session {
+	   id:s.get("session")?.as_str()?.to_string(),
+	   user:s.get("user")?.as_str()?.to_string()
+       }))
+       .collect())
+}
+
+pub fn terminate(session:&Session)->Result<()> {
+    let status = Command::new("loginctl")
+	.args(["terminate-session",&session.id])
+	.status()?;
+    if !status.success() {
+	bail!("Cannot terminate session {}",session.id);
+    }
+    Ok(())
+}
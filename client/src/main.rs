@@ -1,7 +1,16 @@
+use std::{
+    net::TcpStream,
+    time::{
+	SystemTime,
+	UNIX_EPOCH
+    }
+};
 use url::Url;
 use tungstenite::{
     connect,
-    Message
+    stream::MaybeTlsStream,
+    Message,
+    WebSocket
 };
 use pico_args::Arguments;
 use anyhow::{
@@ -9,127 +18,680 @@ use anyhow::{
     bail,
     Result
 };
-use discipline_net::*;
+use tracing::{
+    debug,
+    error,
+    info
+};
+use discipline_net::{
+    *,
+    logging::{
+	LogFormat,
+	LogLevel,
+	LOG_USAGE
+    },
+    profile::{
+	Profile,
+	Profiles,
+	NO_SIGNATURE
+    }
+};
+
+/// Exit code for a failed request, so that scripts can tell errors
+/// apart
+fn exit_code(e:&anyhow::Error)->i32 {
+    if let Some(e) = e.downcast_ref::<RemoteError>() {
+	match e.code {
+	    ErrorCode::UnknownSubject => 10,
+	    ErrorCode::UnknownAdministrator => 11,
+	    ErrorCode::PermissionDenied => 12,
+	    ErrorCode::InvalidArgument => 13,
+	    ErrorCode::LimitExceeded => 14,
+	    ErrorCode::InvalidRequest => 15,
+	    ErrorCode::Internal => 16,
+	    ErrorCode::Unsupported => 17,
+	    ErrorCode::Unknown => 1
+	}
+    } else if e.downcast_ref::<tungstenite::Error>().is_some() {
+	2
+    } else {
+	1
+    }
+}
+
+const USAGE : &str = "\
+Usage: discipline-client COMMAND [--config PATH] [--profile NAME]
+	 [--url ws://ADDR:PORT/] [--subject NAME]
+	 [--sender-admin NAME | --sender-subject NAME]
+	 [--json] [--retry-delay SECONDS] [--loop-delay SECONDS]
+
+Commands:
+  status [--all]                 Time remaining and state of the subject
+  authorize DURATION | --until HH:MM
+  extend DURATION                Add to the time remaining
+  cancel                         Take the remaining time away
+  request DURATION [--reason TEXT]
+  spend DURATION                 Turn banked time into computer time
+  schedule                       Curfews of the coming week
+  history                        Operations on the time bank
+  watch                          Print events as they happen
+  admin add-guest NAME --expires-in DURATION [--max-grant DURATION]
+	[--hours HH:MM-HH:MM] [--all-subjects]
+  admin remove-guest NAME
+  admin credit DURATION [--reason TEXT]
+  admin debit DURATION [--reason TEXT]
+  admin lift-curfew
+
+Durations are in seconds, or e.g. 45m, 2h, 1h30.  The server comes
+from the profile if not given, and so do the sender, its key and the
+subject if no sender is given; profiles are read from --config, by
+default $XDG_CONFIG_HOME/discipline/client.cfg if it exists.  The
+subject otherwise defaults to the sender for subjects.  --json prints the
+responses of the server, one per line.";
+
+/// Duration such as 90, 90s, 45m, 2h or 1h30 (minutes implied)
+fn parse_duration(u:&str)->Result<f64,String> {
+    let invalid = || format!("Invalid duration {:?}",u);
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut last_unit = None;
+    for c in u.chars() {
+	if c.is_ascii_digit() || c == '.' {
+	    number.push(c);
+	    continue;
+	}
+	let unit =
+	    match c {
+		'h' => 3600.0,
+		'm' => 60.0,
+		's' => 1.0,
+		_ => return Err(invalid())
+	    };
+	total += number.parse::<f64>().map_err(|_| invalid())? * unit;
+	number.clear();
+	last_unit = Some(c);
+    }
+    if !number.is_empty() {
+	let unit =
+	    match last_unit {
+		None => 1.0,
+		Some('h') => 60.0,
+		_ => return Err(invalid())
+	    };
+	total += number.parse::<f64>().map_err(|_| invalid())? * unit;
+    } else if last_unit.is_none() {
+	return Err(invalid());
+    }
+    check_duration(total)?;
+    Ok(total)
+}
+
+#[derive(Debug,Clone)]
+enum Action {
+    Status { all:bool },
+    /// --show-time-remaining of the session script
+    TimeRemaining { enforcement:bool },
+    Authorize { duration:f64 },
+    AuthorizeUntil { local_time:WallClock },
+    Extend { duration:f64 },
+    Cancel,
+    Request { duration:f64,reason:String },
+    Spend { amount:f64 },
+    Schedule,
+    History,
+    Watch,
+    AddGuest { name:String,scope:Scope },
+    RemoveGuest { name:String },
+    Credit { amount:f64,reason:String },
+    Debit { amount:f64,reason:String },
+    LiftCurfew
+}
+
+impl Action {
+    fn needs_subject(&self)->bool {
+	!matches!(self,
+		  Self::Status { all:true } |
+		  Self::Watch |
+		  Self::RemoveGuest { .. } |
+		  Self::AddGuest { scope:Scope { subjects:None,.. },.. })
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Output {
+    Text,
+    Json,
+    /// Rust debug output of --get-status, kept for old scripts
+    Debug
+}
+
+fn guest_scope(args:&mut Arguments,subject:Option<&String>)->Result<Scope> {
+    let expires_in : f64 = args.value_from_fn("--expires-in",parse_duration)
+	.map_err(|_| anyhow!("Specify --expires-in for guests"))?;
+    let max_grant : Option<f64> = args.opt_value_from_fn("--max-grant",parse_duration)?;
+    let hours : Option<Hours> = args.opt_value_from_str("--hours")?;
+    let all_subjects = args.contains("--all-subjects");
+    if !all_subjects && subject.is_none() {
+	bail!("Specify --subject or --all-subjects");
+    }
+    // The expiry is sent as a UNIX time, computed once so that
+    // retries do not extend it
+    let t_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+    Ok(Scope {
+	subjects:(!all_subjects).then(|| subject.cloned().into_iter().collect()),
+	max_grant,
+	hours,
+	expires:Some(t_now + expires_in)
+    })
+}
+
+/// Parse a subcommand and its arguments
+fn subcommand(args:&mut Arguments,command:&str,subject:Option<&String>)
+	      ->Result<Action> {
+    let reason = |args:&mut Arguments|->Result<String> {
+	Ok(args.opt_value_from_str("--reason")?.unwrap_or_default())
+    };
+    let action =
+	match command {
+	    "status" => Action::Status { all:args.contains("--all") },
+	    "authorize" =>
+		match args.opt_value_from_str("--until")? {
+		    Some(local_time) => Action::AuthorizeUntil { local_time },
+		    None => Action::Authorize {
+			duration:args.free_from_fn(parse_duration)?
+		    }
+		},
+	    "extend" => Action::Extend { duration:args.free_from_fn(parse_duration)? },
+	    "cancel" => Action::Cancel,
+	    "request" => Action::Request {
+		reason:reason(args)?,
+		duration:args.free_from_fn(parse_duration)?
+	    },
+	    "spend" => Action::Spend { amount:args.free_from_fn(parse_duration)? },
+	    "schedule" => Action::Schedule,
+	    "history" => Action::History,
+	    "watch" => Action::Watch,
+	    "admin" => {
+		let Some(command) = args.subcommand()? else {
+		    bail!("Specify an admin command");
+		};
+		match command.as_str() {
+		    "add-guest" => Action::AddGuest {
+			scope:guest_scope(args,subject)?,
+			name:args.free_from_str()?
+		    },
+		    "remove-guest" => Action::RemoveGuest { name:args.free_from_str()? },
+		    "credit" => Action::Credit {
+			reason:reason(args)?,
+			amount:args.free_from_fn(parse_duration)?
+		    },
+		    "debit" => Action::Debit {
+			reason:reason(args)?,
+			amount:args.free_from_fn(parse_duration)?
+		    },
+		    "lift-curfew" => Action::LiftCurfew,
+		    _ => bail!("Unknown admin command {:?}",command)
+		}
+	    },
+	    _ => bail!("Unknown command {:?}",command)
+	};
+    Ok(action)
+}
+
+/// The original interface, with one flag per action
+fn legacy(args:&mut Arguments,subject:Option<&String>)->Result<(Action,Output)> {
+    let authorize_for : Option<f64> =
+	args.opt_value_from_str("--authorize-for")?;
+    let authorize_until : Option<WallClock> =
+	args.opt_value_from_str("--authorize-until")?;
+    let request_time : Option<f64> =
+	args.opt_value_from_str("--request-time")?;
+    let reason : String = args.opt_value_from_str("--reason")?
+	.unwrap_or_default();
+    let credit : Option<f64> = args.opt_value_from_str("--credit")?;
+    let debit : Option<f64> = args.opt_value_from_str("--debit")?;
+    let spend : Option<f64> = args.opt_value_from_str("--spend")?;
+    let get_ledger = args.contains("--get-ledger");
+    let lift_curfew = args.contains("--lift-curfew");
+    let get_schedule = args.contains("--get-schedule");
+    let add_guest : Option<String> = args.opt_value_from_str("--add-guest")?;
+    let remove_guest : Option<String> =
+	args.opt_value_from_str("--remove-guest")?;
+    let get_status = args.contains("--get-status");
+    let show_time_remaining = args.contains("--show-time-remaining");
+    let show_enforcement = args.contains("--show-enforcement");
+
+    for t in authorize_for.iter().chain(request_time.iter())
+	.chain(credit.iter())
+	.chain(debit.iter())
+	.chain(spend.iter()) {
+	check_duration(*t).map_err(|e| anyhow!("{}",e))?;
+    }
+
+    let action =
+	if let Some(duration) = authorize_for {
+	    Action::Authorize { duration }
+	} else if let Some(local_time) = authorize_until {
+	    Action::AuthorizeUntil { local_time }
+	} else if let Some(duration) = request_time {
+	    Action::Request { duration,reason }
+	} else if credit.is_some() || debit.is_some() {
+	    match (credit,debit) {
+		(Some(amount),None) => Action::Credit { amount,reason },
+		(None,Some(amount)) => Action::Debit { amount,reason },
+		_ => bail!("Specify only one of --credit and --debit")
+	    }
+	} else if let Some(amount) = spend {
+	    Action::Spend { amount }
+	} else if get_ledger {
+	    Action::History
+	} else if lift_curfew {
+	    Action::LiftCurfew
+	} else if get_schedule {
+	    Action::Schedule
+	} else if let Some(name) = add_guest {
+	    Action::AddGuest { scope:guest_scope(args,subject)?,name }
+	} else if let Some(name) = remove_guest {
+	    Action::RemoveGuest { name }
+	} else if show_time_remaining {
+	    Action::TimeRemaining { enforcement:show_enforcement }
+	} else if get_status {
+	    return Ok((Action::Status { all:false },Output::Debug))
+	} else {
+	    bail!("Specify a command, see --help")
+	};
+    Ok((action,Output::Text))
+}
+
+struct Client {
+    socket:WebSocket<MaybeTlsStream<TcpStream>>,
+    sender:Entity,
+    signature:String,
+    encoding:Encoding,
+    features:Vec<Feature>
+}
+
+impl Client {
+    fn connect(url:&Url,sender:Entity,signature:String)->Result<Self> {
+	let (socket,_response) = connect(url.clone())?;
+	debug!(url = %url,"Connected");
+	let mut client = Self { socket,sender,signature,encoding:Encoding::Json,
+				features:Vec::new() };
+	let hello = Command::Hello { version:PROTOCOL_VERSION,
+				     features:Feature::ALL.to_vec() };
+	client.features =
+	    match client.transact(hello) {
+		Ok(Response::Welcome { version,features }) => {
+		    debug!(version,features = ?features,"Handshake");
+		    features
+		},
+		Ok(_) => bail!("Unexpected response"),
+		Err(e) if e.is::<DecodeError>() =>
+		    bail!("No handshake from the server, \
+			   which is probably too old: {}",e),
+		Err(e) => return Err(e)
+	    };
+	// Switch to CBOR if the server supports it
+	if client.features.contains(&Feature::Cbor) {
+	    client.encoding = Encoding::Cbor;
+	}
+	Ok(client)
+    }
+
+    fn require(&self,feature:Feature)->Result<()> {
+	if self.features.contains(&feature) {
+	    Ok(())
+	} else {
+	    bail!("The server does not support {:?}",feature)
+	}
+    }
+
+    fn send(&mut self,payload:Command)->Result<()> {
+	let cmd = Envelope {
+	    sender:self.sender.clone(),
+	    signature:self.signature.clone(),
+	    payload
+	};
+	debug!(sender = %cmd.sender,
+	       command = cmd.payload.name(),
+	       subject = cmd.payload.subject(),
+	       "Sending");
+	let msg =
+	    match self.encoding {
+		Encoding::Json => Message::Text(serde_json::to_string(&cmd)?),
+		Encoding::Cbor => Message::Binary(
+		    Encoding::Cbor.encode(&cmd).map_err(|e| anyhow!(e))?)
+	    };
+	self.socket.send(msg)?;
+	Ok(())
+    }
+
+    fn receive(&mut self)->Result<Response> {
+	let env =
+	    match self.socket.read()? {
+		Message::Text(u) => decode_response(&u)?,
+		Message::Binary(u) => Encoding::Cbor.decode_response(&u)?,
+		_ => bail!("Invalid message type")
+	    };
+	Ok(env.payload.into_result()?)
+    }
+
+    fn transact(&mut self,payload:Command)->Result<Response> {
+	self.send(payload)?;
+	self.receive()
+    }
+}
+
+fn status_text(s:&Status)->String {
+    let mut u = format!("{}: {} left",s.subject,Approx(s.time_remaining));
+    if let Constraint::Curfew { .. } = s.limited_by {
+	u.push_str(&format!(" ({})",s.limited_by));
+    }
+    if s.holiday {
+	u.push_str(", holiday");
+    }
+    match s.last_ping {
+	Some(t) => u.push_str(&format!(", agent seen {} ago",Approx(t))),
+	None => u.push_str(", agent never seen")
+    }
+    if s.bank > 0.0 {
+	u.push_str(&format!(", {} in the bank",Approx(s.bank)));
+    }
+    if s.enforcement == Enforcement::Network {
+	u.push_str(", network only");
+    }
+    u
+}
+
+/// Send a command, printing the response with --json
+fn call(client:&mut Client,output:Output,cmd:Command)->Result<Response> {
+    let resp = client.transact(cmd)?;
+    if output == Output::Json {
+	println!("{}",serde_json::to_string(&resp)?);
+    }
+    Ok(resp)
+}
+
+fn execute(client:&mut Client,action:&Action,subject:&str,output:Output)
+	   ->Result<()> {
+    let subject = subject.to_string();
+    let text = output == Output::Text;
+    let ack = |resp:Response|->Result<()> {
+	match resp {
+	    Response::Ack => Ok(()),
+	    _ => bail!("Unexpected response")
+	}
+    };
+    match action {
+	Action::Status { all:true } => {
+	    client.require(Feature::AllStatus)?;
+	    match call(client,output,Command::GetAllStatus)? {
+		Response::Statuses(statuses) => {
+		    for s in statuses.iter().filter(|_| text) {
+			println!("{}",status_text(s));
+		    }
+		},
+		_ => bail!("Unexpected response")
+	    }
+	},
+	Action::Status { all:false } |
+	Action::TimeRemaining { .. } => {
+	    let resp = call(client,output,Command::GetStatus { subject })?;
+	    let Response::Authorization { subject,time_remaining,last_ping,bank,
+					  limited_by,holiday,enforcement } = &resp
+	    else {
+		bail!("Unexpected response")
+	    };
+	    match (action,output) {
+		(Action::TimeRemaining { enforcement:true },_) =>
+		    println!("{} {}",time_remaining.round() as isize,
+			     enforcement),
+		(Action::TimeRemaining { enforcement:false },_) =>
+		    println!("{}",time_remaining.round() as isize),
+		(_,Output::Debug) => println!("{:#?}",resp),
+		(_,Output::Text) => println!("{}",status_text(&Status {
+		    subject:subject.clone(),
+		    time_remaining:*time_remaining,
+		    last_ping:*last_ping,
+		    bank:*bank,
+		    limited_by:*limited_by,
+		    holiday:*holiday,
+		    enforcement:*enforcement
+		})),
+		(_,Output::Json) => ()
+	    }
+	},
+	Action::Authorize { duration } =>
+	    ack(call(client,output,Command::Authorize { subject,
+					      duration:Some(*duration) })?)?,
+	Action::AuthorizeUntil { local_time } => {
+	    client.require(Feature::Until)?;
+	    match call(client,output,Command::ResolveUntil { subject:subject.clone(),
+						   local_time:*local_time })? {
+		Response::Resolved { description,.. } =>
+		    info!(subject,until = description,"Authorizing"),
+		_ => bail!("Unexpected response")
+	    }
+	    ack(call(client,output,Command::AuthorizeUntil { subject,
+						   local_time:*local_time })?)?
+	},
+	Action::Extend { duration } => {
+	    client.require(Feature::Extend)?;
+	    ack(call(client,output,Command::Extend { subject,
+						     duration:*duration })?)?
+	},
+	Action::Cancel =>
+	    ack(call(client,output,Command::Authorize { subject,duration:None })?)?,
+	Action::Request { duration,reason } => {
+	    client.require(Feature::TimeRequests)?;
+	    ack(call(client,output,Command::RequestTime { subject,
+						duration:*duration,
+						reason:reason.clone() })?)?
+	},
+	Action::Spend { amount } => {
+	    client.require(Feature::Bank)?;
+	    ack(call(client,output,Command::Spend { subject,amount:*amount })?)?
+	},
+	Action::Credit { amount,reason } => {
+	    client.require(Feature::Bank)?;
+	    ack(call(client,output,Command::Credit { subject,amount:*amount,
+					   reason:reason.clone() })?)?
+	},
+	Action::Debit { amount,reason } => {
+	    client.require(Feature::Bank)?;
+	    ack(call(client,output,Command::Debit { subject,amount:*amount,
+					  reason:reason.clone() })?)?
+	},
+	Action::History => {
+	    client.require(Feature::Bank)?;
+	    match call(client,output,Command::GetLedger { subject })? {
+		Response::Ledger { entries,balance,.. } if text => {
+		    for e in entries.iter() {
+			println!("{:.0}\t{:+.0}\t{:.0}\t{}\t{}",
+				 e.time,e.amount,e.balance,e.by,e.reason);
+		    }
+		    println!("Balance: {:.0}",balance);
+		},
+		Response::Ledger { .. } => (),
+		_ => bail!("Unexpected response")
+	    }
+	},
+	Action::LiftCurfew => {
+	    client.require(Feature::Curfew)?;
+	    ack(call(client,output,Command::LiftCurfew { subject })?)?
+	},
+	Action::Schedule => {
+	    client.require(Feature::Schedule)?;
+	    match call(client,output,Command::GetSchedule { subject })? {
+		Response::Schedule { days,.. } if text => {
+		    for day in days.iter() {
+			println!("{}{}",day.date,
+				 if day.holiday { " (holiday)" } else { "" });
+			for w in day.curfews.iter() {
+			    println!("\tCurfew {}{}",w.description,
+				     if w.lifted { " (lifted)" } else { "" });
+			}
+		    }
+		},
+		Response::Schedule { .. } => (),
+		_ => bail!("Unexpected response")
+	    }
+	},
+	Action::AddGuest { name,scope } => {
+	    client.require(Feature::Guests)?;
+	    ack(call(client,output,Command::AddGuest { name:name.clone(),
+					     scope:scope.clone() })?)?
+	},
+	Action::RemoveGuest { name } => {
+	    client.require(Feature::Guests)?;
+	    ack(call(client,output,Command::RemoveGuest { name:name.clone() })?)?
+	},
+	Action::Watch => {
+	    client.require(Feature::Events)?;
+	    ack(client.transact(Command::Subscribe)?)?;
+	    loop {
+		let resp = client.receive()?;
+		match &resp {
+		    Response::Event(_) if output == Output::Json =>
+			println!("{}",serde_json::to_string(&resp)?),
+		    Response::Event(event) => println!("{}",event),
+		    _ => ()
+		}
+	    }
+	}
+    }
+    Ok(())
+}
 
 fn main()->Result<()> {
-    let progname : String = std::env::args().nth(0).unwrap();
-    
     let mut args = Arguments::from_env();
 
     if args.contains("-h") || args.contains("--help") {
-	eprintln!("Usage: {} [--url ws://ADDR:PORT/]",
-		  progname);
+	eprintln!("{}\n\nOptions: {}",USAGE,LOG_USAGE);
 	return Ok(())
     }
 
-    let sender =
+    let command = args.subcommand()?;
+
+    let config_path : Option<String> = args.opt_value_from_str("--config")?;
+    let profile_name : Option<String> = args.opt_value_from_str("--profile")?;
+    let sender : Option<Entity> =
 	if let Some(u) = args.opt_value_from_str("--sender-subject")? {
-	    Entity::Subject(u)
+	    Some(Entity::Subject(u))
 	} else {
-	    if let Some(u) = args.opt_value_from_str("--sender-admin")? {
-		Entity::Administrator(u)
-	    } else {
-		bail!("Specify --sender-subject or --sender-admin")
+	    args.opt_value_from_str("--sender-admin")?
+		.map(Entity::Administrator)
+	};
+
+    // The default configuration file is only read when a profile is
+    // asked for or needed for the sender, so that the session script
+    // is not affected by that of its user
+    let profile : Option<Profile> =
+	match config_path {
+	    Some(path) => Some(Profiles::load(path)),
+	    None => {
+		let path = Profiles::default_path();
+		(profile_name.is_some() || sender.is_none() && path.exists())
+		    .then(|| Profiles::load(path))
 	    }
+	}
+	.transpose()
+	.and_then(|profiles| profiles
+		  .map(|p| p.get(profile_name.as_deref()).cloned())
+		  .transpose())
+	.map_err(|e| anyhow!("{}",e))?;
+
+    // The key and the subject of the profile go with its identity
+    let identity : Option<&Profile> =
+	profile.as_ref().filter(|_| sender.is_none());
+    let sender =
+	match (sender,identity) {
+	    (Some(sender),_) => sender,
+	    (None,Some(p)) => p.identity.clone(),
+	    (None,None) =>
+		bail!("Specify --sender-subject, --sender-admin or --profile")
 	};
 
-    let subject : String = args.value_from_str("--subject")?;
+    let subject : Option<String> = args.opt_value_from_str("--subject")?
+	.or_else(|| identity.and_then(|p| p.subject.clone()))
+	.or_else(|| match &sender {
+	    Entity::Subject(name) => Some(name.clone()),
+	    _ => None
+	});
 
     let retry_delay : Option<f64> = args.opt_value_from_str("--retry-delay")?;
     let loop_delay : Option<f64> = args.opt_value_from_str("--loop-delay")?;
-
-    let authorize_for : Option<f64> =
-	args.opt_value_from_str("--authorize-for")?;
-    let get_status = args.contains("--get-status");
-    let show_time_remaining = args.contains("--show-time-remaining");
+    let json = args.contains("--json");
 
     let url = args.opt_value_from_str("--url")?
+	.or_else(|| profile.as_ref().map(|p| p.url.clone()))
 	.unwrap_or_else(|| "ws://127.0.0.1:9001".to_string());
 
     let url = Url::parse(&url)?;
 
+    let signature =
+	match identity {
+	    Some(p) => p.signature(&url).map_err(|e| anyhow!("{}",e))?,
+	    None => NO_SIGNATURE.to_string()
+	};
+
+    let log_level : LogLevel = args.opt_value_from_str("--log-level")?
+	.unwrap_or(LogLevel::INFO);
+    let log_format : LogFormat = args.opt_value_from_str("--log-format")?
+	.unwrap_or(LogFormat::Text);
+
+    let (action,output) =
+	match &command {
+	    Some(command) => {
+		let action = subcommand(&mut args,command,subject.as_ref())?;
+		(action,if json { Output::Json } else { Output::Text })
+	    },
+	    None => legacy(&mut args,subject.as_ref())?
+	};
+
     let rest = args.finish();
     if !rest.is_empty() {
 	bail!("Invalid arguments: {:?}",rest);
     }
 
-    let process = move ||->Result<()> {
-	let (mut socket,_response) = connect(url.clone())?;
-	// eprintln!("Response: {:#?}",response);
-
-	let mut transact = |payload:Command|->Result<Envelope<Response>> {
-	    let sender = sender.clone();
-	    let cmd = Envelope {
-		sender,
-		signature:"\\_'')_/".to_string(),
-		payload
-	    };
-	    let v = serde_json::to_string(&cmd)?;
-	    socket.send(Message::Text(v))?;
-	    let msg = socket.read()?;
-	    match msg {
-		Message::Text(u) => {
-		    let resp : Result<Envelope<Response>,String> = serde_json::from_str(&u)
-			.map_err(|e| anyhow!("Invalid JSON: {}",e))?;
-		    match resp {
-			Ok(env) => Ok(env),
-			Err(e) => bail!("Error: {}",e)
-		    }
-		},
-		_ => bail!("Invalid message type")
-	    }
+    let subject =
+	match subject {
+	    Some(subject) => subject,
+	    None if action.needs_subject() => bail!("Specify --subject"),
+	    None => String::new()
 	};
 
-	loop {
-	    let subject = subject.clone();
-	    if let Some(d) = authorize_for {
-		let env = transact(Command::Authorize { subject,duration:Some(d) })?;
-		match &env.payload {
-		    Response::Ack => (),
-		    Response::Error(e) => bail!("Remote error: {}",e),
-		    _ => bail!("Unexpected response")
-		}
-	    } else if get_status || show_time_remaining {
-		let env = transact(Command::GetStatus { subject })?;
-		match &env.payload {
-		    Response::Authorization {
-			subject:_,
-			time_remaining,
-			last_ping:_
-		    } => {
-			if show_time_remaining {
-			    println!("{}",time_remaining.round() as isize);
-			} else {
-			    println!("{:#?}",env.payload);
-			}
-		    },
-		    Response::Error(e) => bail!("Remote error: {}",e),
-		    _ => bail!("Unexpected response")
-		}
-	    } else {
-		bail!("Specify --get-status, --show-time-remaining or --authorize-for")
-	    }
+    discipline_net::logging::init(log_level,log_format)?;
 
+    let process = || -> Result<()> {
+	let mut client = Client::connect(&url,sender.clone(),signature.clone())?;
+	loop {
+	    execute(&mut client,&action,&subject,output)?;
 	    if let Some(d) = loop_delay {
 		std::thread::sleep(std::time::Duration::from_secs_f64(d))
 	    } else {
 		break;
 	    }
 	}
-
-	socket.close(None)?;
-
+	client.socket.close(None)?;
 	Ok(())
     };
 
     loop {
-	match process() {
-	    Err(e) => eprintln!("Error: {}",e),
-	    Ok(()) => ()
+	let res = process();
+	if let Err(e) = &res {
+	    error!(subject,error = %e,"Request failed");
+	    // No point in retrying if the server will refuse again
+	    let permanent = e.downcast_ref::<RemoteError>()
+		.map(|e| !e.code.is_transient())
+		.unwrap_or(false);
+	    if permanent || retry_delay.is_none() {
+		std::process::exit(exit_code(e));
+	    }
 	}
 
 	if let Some(d) = retry_delay {
-	    eprintln!("Waiting for retry...");
+	    info!(delay = d,"Waiting for retry");
 	    std::thread::sleep(std::time::Duration::from_secs_f64(d))
 	} else {
 	    break;
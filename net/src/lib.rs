@@ -1,13 +1,40 @@
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(feature = "profile")]
+pub mod profile;
+
 use serde::{
+    de::DeserializeOwned,
     Deserialize,
     Serialize
 };
+use std::{
+    collections::BTreeMap,
+    fmt::{
+	Display,
+	Formatter
+    },
+    str::FromStr
+};
 
 #[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum Entity {
     Controller,
     Administrator(String),
-    Subject(String)
+    Subject(String),
+    /// Enforcement agent of a machine shared by several subjects
+    Agent(String)
+}
+
+impl Display for Entity {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	match self {
+	    Self::Controller => write!(o,"controller"),
+	    Self::Administrator(name) => write!(o,"admin:{}",name),
+	    Self::Subject(name) => write!(o,"subject:{}",name),
+	    Self::Agent(name) => write!(o,"agent:{}",name)
+	}
+    }
 }
 
 #[derive(Debug,Serialize,Deserialize,Clone)]
@@ -17,20 +44,961 @@ pub struct Envelope<T> {
     pub signature:String
 }
 
+/// Version of the protocol spoken by this build.  Version 1 is the
+/// original protocol without a handshake, assumed for peers that
+/// do not send `Hello`.
+pub const PROTOCOL_VERSION : u32 = 2;
+
+/// Optional capabilities announced during the handshake
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum Feature {
+    /// Subscribe and Event
+    Events,
+    /// AuthorizeUntil and ResolveUntil
+    Until,
+    /// RequestTime
+    TimeRequests,
+    /// CBOR-encoded envelopes in binary messages
+    Cbor,
+    /// GetAllStatus
+    AllStatus,
+    /// AddGuest and RemoveGuest
+    Guests,
+    /// Credit, Debit, Spend and GetLedger
+    Bank,
+    /// LiftCurfew
+    Curfew,
+    /// GetSchedule
+    Schedule,
+    /// ReportUsage
+    AppBudgets,
+    /// Agent senders and GetStatuses
+    Agents,
+    /// GetAlerts
+    Alerts,
+    /// Extend
+    Extend,
+    /// CheckLogin
+    LoginCheck,
+    /// Feature of a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+impl Feature {
+    /// Features implemented by this build
+    pub const ALL : &'static [Feature] = &[
+	Feature::Events,
+	Feature::Until,
+	Feature::TimeRequests,
+	Feature::Cbor,
+	Feature::AllStatus,
+	Feature::Guests,
+	Feature::Bank,
+	Feature::Curfew,
+	Feature::Schedule,
+	Feature::AppBudgets,
+	Feature::Agents,
+	Feature::Alerts,
+	Feature::Extend,
+	Feature::LoginCheck
+    ];
+}
+
 #[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum Command {
+    /// Handshake, to be sent first on a connection
+    Hello { version:u32,
+	    features:Vec<Feature> },
     Authorize { subject:String,
 		duration:Option<f64> },
+    AuthorizeUntil { subject:String,
+		     local_time:WallClock },
+    /// Add to the authorization of a subject, regardless of curfews
+    Extend { subject:String,
+	     duration:f64 },
+    ResolveUntil { subject:String,
+		   local_time:WallClock },
     GetStatus { subject:String },
+    /// Same as GetStatus for a subject trying to log in, which does
+    /// not count as a ping from its session
+    CheckLogin { subject:String },
+    /// Status of every subject the sender may see
+    GetAllStatus,
+    Subscribe,
+    RequestTime { subject:String,
+		  duration:f64,
+		  reason:String },
+    /// Create or replace a temporary administrator
+    AddGuest { name:String,
+	       scope:Scope },
+    RemoveGuest { name:String },
+    /// Add time to the bank of a subject, e.g. for chores
+    Credit { subject:String,
+	     amount:f64,
+	     reason:String },
+    /// Take time from the bank of a subject, possibly below zero
+    Debit { subject:String,
+	    amount:f64,
+	    reason:String },
+    /// Turn banked time into an authorization
+    Spend { subject:String,
+	    amount:f64 },
+    GetLedger { subject:String },
+    /// Lift the curfew in force, or the next one, for one night
+    LiftCurfew { subject:String },
+    /// Effective rules of a subject for the coming week
+    GetSchedule { subject:String },
+    /// Time spent in each application category since the last
+    /// report, sent by the agent; answered with AppBudgets
+    ReportUsage { subject:String,
+		  usage:Vec<AppUsage> },
+    /// Status of several subjects in one request; answered with
+    /// Statuses
+    GetStatuses { subjects:Vec<String> },
+    /// Alerts to sound as the time of a subject runs out
+    GetAlerts { subject:String },
+}
+
+impl Command {
+    pub const NAMES : &'static [&'static str] = &[
+	"Hello",
+	"Authorize",
+	"AuthorizeUntil",
+	"Extend",
+	"ResolveUntil",
+	"GetStatus",
+	"CheckLogin",
+	"GetAllStatus",
+	"Subscribe",
+	"RequestTime",
+	"AddGuest",
+	"RemoveGuest",
+	"Credit",
+	"Debit",
+	"Spend",
+	"GetLedger",
+	"LiftCurfew",
+	"GetSchedule",
+	"ReportUsage",
+	"GetStatuses",
+	"GetAlerts"
+    ];
+
+    /// Short name of the command, for diagnostics
+    pub fn name(&self)->&'static str {
+	match self {
+	    Self::Hello { .. } => "Hello",
+	    Self::Authorize { .. } => "Authorize",
+	    Self::AuthorizeUntil { .. } => "AuthorizeUntil",
+	    Self::Extend { .. } => "Extend",
+	    Self::ResolveUntil { .. } => "ResolveUntil",
+	    Self::GetStatus { .. } => "GetStatus",
+	    Self::CheckLogin { .. } => "CheckLogin",
+	    Self::GetAllStatus => "GetAllStatus",
+	    Self::Subscribe => "Subscribe",
+	    Self::RequestTime { .. } => "RequestTime",
+	    Self::AddGuest { .. } => "AddGuest",
+	    Self::RemoveGuest { .. } => "RemoveGuest",
+	    Self::Credit { .. } => "Credit",
+	    Self::Debit { .. } => "Debit",
+	    Self::Spend { .. } => "Spend",
+	    Self::GetLedger { .. } => "GetLedger",
+	    Self::LiftCurfew { .. } => "LiftCurfew",
+	    Self::GetSchedule { .. } => "GetSchedule",
+	    Self::ReportUsage { .. } => "ReportUsage",
+	    Self::GetStatuses { .. } => "GetStatuses",
+	    Self::GetAlerts { .. } => "GetAlerts"
+	}
+    }
+
+    pub fn subject(&self)->Option<&str> {
+	match self {
+	    Self::Authorize { subject,.. } |
+	    Self::AuthorizeUntil { subject,.. } |
+	    Self::Extend { subject,.. } |
+	    Self::ResolveUntil { subject,.. } |
+	    Self::GetStatus { subject } |
+	    Self::CheckLogin { subject } |
+	    Self::RequestTime { subject,.. } |
+	    Self::Credit { subject,.. } |
+	    Self::Debit { subject,.. } |
+	    Self::Spend { subject,.. } |
+	    Self::GetLedger { subject } |
+	    Self::LiftCurfew { subject } |
+	    Self::GetSchedule { subject } |
+	    Self::ReportUsage { subject,.. } |
+	    Self::GetAlerts { subject } => Some(subject),
+	    Self::Hello { .. } |
+	    Self::GetAllStatus |
+	    Self::GetStatuses { .. } |
+	    Self::Subscribe |
+	    Self::AddGuest { .. } |
+	    Self::RemoveGuest { .. } => None
+	}
+    }
+}
+
+/// Longest authorization that makes sense at all
+pub const MAX_DURATION : f64 = 7.0 * 86400.0;
+
+/// Check that a duration can be sent in an authorization; note that
+/// JSON has no representation for NaN or infinities
+pub fn check_duration(t:f64)->Result<(),String> {
+    if !t.is_finite() || t < 0.0 {
+	Err(format!("Invalid duration {}",t))
+    } else if t > MAX_DURATION {
+	Err(format!("Duration {} is too long",Approx(t)))
+    } else {
+	Ok(())
+    }
+}
+
+/// Time of day in the household time zone
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub struct WallClock {
+    pub hour:u8,
+    pub minute:u8
+}
+
+impl WallClock {
+    pub fn new(hour:u8,minute:u8)->Result<Self,String> {
+	if hour >= 24 {
+	    Err(format!("Invalid hour {}",hour))
+	} else if minute >= 60 {
+	    Err(format!("Invalid minute {}",minute))
+	} else {
+	    Ok(Self { hour,minute })
+	}
+    }
+}
+
+impl FromStr for WallClock {
+    type Err = String;
+
+    /// Parse "HH:MM" or "HH"
+    fn from_str(u:&str)->Result<Self,String> {
+	let (h,m) = u.trim().split_once(':').unwrap_or((u.trim(),"0"));
+	let hour = h.parse::<u8>()
+	    .map_err(|_| format!("Invalid hour {:?}",h))?;
+	let minute = m.parse::<u8>()
+	    .map_err(|_| format!("Invalid minute {:?}",m))?;
+	Self::new(hour,minute)
+    }
+}
+
+impl Display for WallClock {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	write!(o,"{:02}:{:02}",self.hour,self.minute)
+    }
+}
+
+/// Daily window of wall-clock times, which may span midnight
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub struct Hours {
+    pub from:WallClock,
+    pub to:WallClock
+}
+
+impl Hours {
+    pub fn contains(&self,wc:WallClock)->bool {
+	if self.from <= self.to {
+	    self.from <= wc && wc < self.to
+	} else {
+	    wc >= self.from || wc < self.to
+	}
+    }
+}
+
+impl FromStr for Hours {
+    type Err = String;
+
+    /// Parse "HH:MM-HH:MM"
+    fn from_str(u:&str)->Result<Self,String> {
+	let (from,to) = u.split_once('-')
+	    .ok_or_else(|| format!("Invalid hours {:?}, expected HH:MM-HH:MM",u))?;
+	Ok(Self { from:from.parse()?,to:to.parse()? })
+    }
+}
+
+impl Display for Hours {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	write!(o,"{}-{}",self.from,self.to)
+    }
+}
+
+/// Restrictions of an administrator; the default is full rights
+#[derive(Debug,Serialize,Deserialize,Clone,Default)]
+pub struct Scope {
+    /// Subjects the administrator may see and authorize, all of them
+    /// if None
+    #[serde(default)]
+    pub subjects:Option<Vec<String>>,
+    /// Longest single authorization, on top of the policy of the
+    /// subject
+    #[serde(default)]
+    pub max_grant:Option<f64>,
+    /// Time of day during which the administrator may act
+    #[serde(default)]
+    pub hours:Option<Hours>,
+    /// UNIX time after which the administrator has no rights
+    #[serde(default)]
+    pub expires:Option<f64>
+}
+
+impl Scope {
+    pub fn covers(&self,subject:&str)->bool {
+	self.subjects.as_ref()
+	    .map(|subjects| subjects.iter().any(|s| s == subject))
+	    .unwrap_or(true)
+    }
+
+    /// Check that the scope is in force at the given time
+    pub fn check(&self,t_now:f64,local_time:WallClock)->Result<(),String> {
+	if let Some(t) = self.expires {
+	    if t_now >= t {
+		return Err("Access has expired".to_string());
+	    }
+	}
+	if let Some(hours) = self.hours {
+	    if !hours.contains(local_time) {
+		return Err(format!("Access is limited to {}",hours));
+	    }
+	}
+	Ok(())
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum ErrorCode {
+    UnknownSubject,
+    UnknownAdministrator,
+    PermissionDenied,
+    InvalidArgument,
+    LimitExceeded,
+    /// The message could not be understood
+    InvalidRequest,
+    /// The message is well-formed but uses a command or a feature
+    /// that the other side does not implement
+    Unsupported,
+    Internal,
+    /// Code of a newer peer
+    #[serde(other)]
+    Unknown
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed later
+    pub fn is_transient(&self)->bool {
+	matches!(self,Self::Internal)
+    }
+}
+
+/// Error reported by the other side
+#[derive(Debug,Clone)]
+pub struct RemoteError {
+    pub code:ErrorCode,
+    pub message:String
+}
+
+impl Display for RemoteError {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	write!(o,"{:?}: {}",self.code,self.message)
+    }
+}
+
+impl std::error::Error for RemoteError { }
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct Status {
+    pub subject:String,
+    pub time_remaining:f64,
+    /// Time since the last message from the agent of the subject
+    pub last_ping:Option<f64>,
+    /// Balance of the time bank
+    #[serde(default)]
+    pub bank:f64,
+    #[serde(default)]
+    pub limited_by:Constraint,
+    /// Whether the holiday rules apply today
+    #[serde(default)]
+    pub holiday:bool,
+    #[serde(default)]
+    pub enforcement:Enforcement
+}
+
+/// Curfew occurrence in a schedule
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct Window {
+    pub start:f64,
+    pub end:f64,
+    /// Start and end in the household time zone
+    pub description:String,
+    /// Lifted by an administrator
+    pub lifted:bool
+}
+
+/// Group of applications sharing a daily budget, e.g. games
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct AppCategory {
+    pub name:String,
+    /// Process names (as in /proc/PID/comm) or executable file names
+    pub processes:Vec<String>,
+    /// Time allowed per day, unlimited if None
+    #[serde(default)]
+    pub daily_limit:Option<f64>
+}
+
+impl AppCategory {
+    pub fn matches(&self,comm:&str,exe:Option<&str>)->bool {
+	self.processes.iter().any(|p| p == comm || Some(p.as_str()) == exe)
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct AppUsage {
+    pub category:String,
+    pub seconds:f64
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct AppBudget {
+    pub category:AppCategory,
+    pub used_today:f64,
+    /// Time left today, unlimited if None
+    pub remaining:Option<f64>
+}
+
+impl AppBudget {
+    pub fn exhausted(&self)->bool {
+	self.remaining.map(|t| t <= 0.0).unwrap_or(false)
+    }
+}
+
+/// Two-tone beeps with smooth edges, repeated
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+#[serde(default)]
+pub struct Beep {
+    /// Frequencies and amplitudes of the two tones
+    pub f1:f64,
+    pub a1:f64,
+    pub f2:f64,
+    pub a2:f64,
+    /// Duration of each beep and of the silence after it
+    pub on:f64,
+    pub off:f64,
+    /// Rise and fall time
+    pub edge:f64,
+    pub count:u32
+}
+
+impl Beep {
+    /// Sampling rate of the synthesized sound
+    pub const SAMPLE_RATE : u32 = 44100;
+
+    pub fn check(&self)->Result<(),String> {
+	let nyquist = Self::SAMPLE_RATE as f64/2.0;
+	for f in [self.f1,self.f2] {
+	    if !(f > 0.0 && f < nyquist) {
+		return Err(format!("Invalid frequency {}",f));
+	    }
+	}
+	for a in [self.a1,self.a2] {
+	    if !(0.0..=1.0).contains(&a) {
+		return Err(format!("Invalid amplitude {}",a));
+	    }
+	}
+	if !(self.on > 0.0 && self.on <= 10.0 &&
+	     self.off >= 0.0 && self.off <= 10.0) {
+	    return Err("Invalid beep duration".to_string());
+	}
+	if !(self.edge > 0.0 && 2.0*self.edge <= self.on) {
+	    return Err(format!("Invalid edge duration {}",self.edge));
+	}
+	if !(1..=20).contains(&self.count) {
+	    return Err(format!("Invalid beep count {}",self.count));
+	}
+	Ok(())
+    }
+}
+
+impl Default for Beep {
+    fn default()->Self {
+	Self {
+	    f1:440.0,
+	    a1:0.7,
+	    f2:1500.0,
+	    a2:0.3,
+	    on:1.0,
+	    off:0.5,
+	    edge:1.0/250.0,
+	    count:5
+	}
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub enum Sound {
+    Beep(Beep),
+    /// WAV or OGG file on the machine of the agent
+    File(String)
+}
+
+impl Default for Sound {
+    fn default()->Self {
+	Self::Beep(Beep::default())
+    }
+}
+
+/// Sound played once the time remaining falls below a threshold
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub struct Alert {
+    pub before:f64,
+    #[serde(default)]
+    pub sound:Sound,
+    /// Message spoken after the sound, by language, where {name} and
+    /// {minutes} stand for the name of the subject and the minutes
+    /// left
+    #[serde(default)]
+    pub speech:BTreeMap<String,String>
+}
+
+impl Alert {
+    pub fn check(&self)->Result<(),String> {
+	check_duration(self.before)?;
+	match &self.sound {
+	    Sound::Beep(beep) => beep.check(),
+	    Sound::File(path) if path.is_empty() =>
+		Err("Empty sound file name".to_string()),
+	    Sound::File(_) => Ok(())
+	}
+    }
+}
+
+/// Effective rules of a subject on one day
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct DaySchedule {
+    /// Date in the household time zone, as YYYY-MM-DD
+    pub date:String,
+    pub holiday:bool,
+    /// Curfews starting on that day
+    pub curfews:Vec<Window>
+}
+
+/// What ends the time remaining of a subject
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq,Default)]
+pub enum Constraint {
+    /// The authorization itself
+    #[default]
+    Authorization,
+    /// A curfew cuts the authorization short
+    Curfew { from:WallClock,
+	     to:WallClock }
+}
+
+impl Display for Constraint {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	match self {
+	    Self::Authorization => write!(o,"authorization"),
+	    Self::Curfew { from,to } => write!(o,"curfew {}-{}",from,to)
+	}
+    }
+}
+
+/// What the agent does when the time of a subject runs out
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq,Default)]
+pub enum Enforcement {
+    /// Terminate the sessions of the subject
+    #[default]
+    Logout,
+    /// Block outbound network traffic from the subject, which can
+    /// keep working offline
+    Network
+}
+
+impl Display for Enforcement {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	match self {
+	    Self::Logout => write!(o,"logout"),
+	    Self::Network => write!(o,"network")
+	}
+    }
+}
+
+/// Credit or debit of the time bank of a subject
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct LedgerEntry {
+    pub time:f64,
+    /// Positive for credits, negative for debits and spending
+    pub amount:f64,
+    /// Balance after the operation
+    pub balance:f64,
+    /// Who made the operation
+    pub by:String,
+    pub reason:String
 }
 
 #[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum Response {
+    /// Reply to Hello, with the version that will be spoken on the
+    /// connection and the features of the server
+    Welcome {
+	version:u32,
+	features:Vec<Feature>
+    },
     Ack,
-    Error(String),
+    Error {
+	code:ErrorCode,
+	message:String
+    },
     Authorization {
 	subject:String,
 	time_remaining:f64,
-	last_ping:Option<f64>
+	last_ping:Option<f64>,
+	#[serde(default)]
+	bank:f64,
+	#[serde(default)]
+	limited_by:Constraint,
+	#[serde(default)]
+	holiday:bool,
+	#[serde(default)]
+	enforcement:Enforcement
+    },
+    Statuses(Vec<Status>),
+    Ledger {
+	subject:String,
+	balance:f64,
+	entries:Vec<LedgerEntry>
     },
+    Schedule {
+	subject:String,
+	days:Vec<DaySchedule>
+    },
+    AppBudgets {
+	subject:String,
+	budgets:Vec<AppBudget>
+    },
+    Alerts {
+	subject:String,
+	alerts:Vec<Alert>
+    },
+    Event(Event),
+    /// Next occurrence of a wall-clock time, as a UNIX time and in
+    /// the household time zone
+    Resolved {
+	subject:String,
+	local_time:WallClock,
+	until:f64,
+	description:String
+    },
+}
+
+impl Response {
+    pub const NAMES : &'static [&'static str] = &[
+	"Welcome",
+	"Ack",
+	"Error",
+	"Authorization",
+	"Statuses",
+	"Ledger",
+	"Schedule",
+	"AppBudgets",
+	"Alerts",
+	"Event",
+	"Resolved"
+    ];
+
+    pub fn error<U:Into<String>>(code:ErrorCode,message:U)->Self {
+	Self::Error { code,message:message.into() }
+    }
+
+    /// Turn error responses into errors
+    pub fn into_result(self)->Result<Self,RemoteError> {
+	match self {
+	    Self::Error { code,message } => Err(RemoteError { code,message }),
+	    _ => Ok(self)
+	}
+    }
+}
+
+/// Why a message could not be decoded
+#[derive(Debug,Clone)]
+pub enum DecodeError {
+    Invalid(String),
+    /// Well-formed message with a variant unknown to this version
+    Unsupported(String)
+}
+
+impl Display for DecodeError {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	match self {
+	    Self::Invalid(e) => write!(o,"Invalid message: {}",e),
+	    Self::Unsupported(name) => write!(o,"Unsupported message {:?}",name)
+	}
+    }
+}
+
+impl std::error::Error for DecodeError { }
+
+impl DecodeError {
+    pub fn code(&self)->ErrorCode {
+	match self {
+	    Self::Invalid(_) => ErrorCode::InvalidRequest,
+	    Self::Unsupported(_) => ErrorCode::Unsupported
+	}
+    }
+}
+
+/// Wire encoding of a message
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Encoding {
+    /// JSON in text messages
+    Json,
+    /// CBOR in binary messages, much more compact for the short
+    /// messages that agents send all the time
+    Cbor
+}
+
+impl Encoding {
+    pub fn encode<T:Serialize>(&self,v:&T)->Result<Vec<u8>,String> {
+	match self {
+	    Self::Json => serde_json::to_vec(v).map_err(|e| e.to_string()),
+	    Self::Cbor => {
+		let mut buf = Vec::new();
+		ciborium::ser::into_writer(v,&mut buf)
+		    .map_err(|e| e.to_string())?;
+		Ok(buf)
+	    }
+	}
+    }
+
+    /// CBOR is decoded straight into the message type: going through
+    /// JSON values would turn a NaN into null, and thus e.g. an
+    /// invalid duration into a cancellation
+    fn decode<T:DeserializeOwned>(&self,u:&[u8],names:&[&str])
+				  ->Result<Envelope<T>,DecodeError> {
+	match self {
+	    Self::Json => {
+		let env = serde_json::from_slice(u)
+		    .map_err(|e| DecodeError::Invalid(e.to_string()))?;
+		decode(env,names)
+	    },
+	    Self::Cbor => {
+		let e =
+		    match ciborium::de::from_reader(u) {
+			Ok(env) => return Ok(env),
+			Err(e) => DecodeError::Invalid(e.to_string())
+		    };
+		let env : Envelope<ciborium::Value> = ciborium::de::from_reader(u)
+		    .map_err(|_| e.clone())?;
+		let tag =
+		    match &env.payload {
+			ciborium::Value::Text(tag) => Some(tag.as_str()),
+			ciborium::Value::Map(map) if map.len() == 1 =>
+			    map[0].0.as_text(),
+			_ => None
+		    };
+		match tag {
+		    Some(tag) if !names.contains(&tag) =>
+			Err(DecodeError::Unsupported(tag.to_string())),
+		    _ => Err(e)
+		}
+	    }
+	}
+    }
+
+    pub fn decode_command(&self,u:&[u8])->Result<Envelope<Command>,DecodeError> {
+	self.decode(u,Command::NAMES)
+    }
+
+    pub fn decode_response(&self,u:&[u8])->Result<Envelope<Response>,DecodeError> {
+	self.decode(u,Response::NAMES)
+    }
+}
+
+/// Decode an envelope; payloads of an unknown variant are reported as
+/// unsupported rather than as malformed
+fn decode<T:DeserializeOwned>(env:Envelope<serde_json::Value>,names:&[&str])
+				->Result<Envelope<T>,DecodeError> {
+    let tag =
+	match &env.payload {
+	    serde_json::Value::String(tag) => Some(tag),
+	    serde_json::Value::Object(map) if map.len() == 1 =>
+		map.keys().next(),
+	    _ => None
+	};
+    if let Some(tag) = tag {
+	if !names.contains(&tag.as_str()) {
+	    return Err(DecodeError::Unsupported(tag.to_string()));
+	}
+    }
+    let payload = serde_json::from_value(env.payload)
+	.map_err(|e| DecodeError::Invalid(e.to_string()))?;
+    Ok(Envelope { sender:env.sender,payload,signature:env.signature })
+}
+
+pub fn decode_command(u:&str)->Result<Envelope<Command>,DecodeError> {
+    Encoding::Json.decode_command(u.as_bytes())
+}
+
+pub fn decode_response(u:&str)->Result<Envelope<Response>,DecodeError> {
+    Encoding::Json.decode_response(u.as_bytes())
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub enum Event {
+    AgentSilent { subject:String,
+		  silent_for:f64 },
+    AgentResumed { subject:String },
+    AuthorizationGranted { subject:String,
+			   administrator:String,
+			   duration:Option<f64> },
+    TimeExpired { subject:String },
+    TimeRequested { subject:String,
+		    duration:f64,
+		    reason:String },
+    BankChanged { subject:String,
+		  entry:LedgerEntry },
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+pub enum EventKind {
+    AgentSilent,
+    AgentResumed,
+    AuthorizationGranted,
+    TimeExpired,
+    TimeRequested,
+    BankChanged,
+}
+
+impl Event {
+    /// Feature the peer must have announced to be sent the event,
+    /// for events added after the handshake
+    pub fn feature(&self)->Option<Feature> {
+	match self {
+	    Self::BankChanged { .. } => Some(Feature::Bank),
+	    _ => None
+	}
+    }
+
+    pub fn kind(&self)->EventKind {
+	match self {
+	    Self::AgentSilent { .. } => EventKind::AgentSilent,
+	    Self::AgentResumed { .. } => EventKind::AgentResumed,
+	    Self::AuthorizationGranted { .. } => EventKind::AuthorizationGranted,
+	    Self::TimeExpired { .. } => EventKind::TimeExpired,
+	    Self::TimeRequested { .. } => EventKind::TimeRequested,
+	    Self::BankChanged { .. } => EventKind::BankChanged
+	}
+    }
+
+    pub fn subject(&self)->&str {
+	match self {
+	    Self::AgentSilent { subject,.. } |
+	    Self::AgentResumed { subject } |
+	    Self::AuthorizationGranted { subject,.. } |
+	    Self::TimeExpired { subject } |
+	    Self::TimeRequested { subject,.. } |
+	    Self::BankChanged { subject,.. } => subject
+	}
+    }
+}
+
+/// Rough human-readable duration, e.g. "1h05", "12 min" or "40 s"
+pub struct Approx(pub f64);
+
+impl Display for Approx {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	let t = self.0.round() as i64;
+	if t < 60 {
+	    write!(o,"{} s",t)
+	} else if t < 3600 {
+	    write!(o,"{} min",t/60)
+	} else {
+	    write!(o,"{}h{:02}",t/3600,(t%3600)/60)
+	}
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self,o:&mut Formatter<'_>)->std::fmt::Result {
+	match self {
+	    Self::AgentSilent { subject,silent_for } =>
+		write!(o,"Agent of {} silent for {}",
+		       subject,Approx(*silent_for)),
+	    Self::AgentResumed { subject } =>
+		write!(o,"Agent of {} is back",subject),
+	    Self::AuthorizationGranted { subject,administrator,
+					 duration:Some(t) } =>
+		write!(o,"{} authorized {} for {}",
+		       administrator,subject,Approx(*t)),
+	    Self::AuthorizationGranted { subject,administrator,
+					 duration:None } =>
+		write!(o,"{} cancelled the authorization of {}",
+		       administrator,subject),
+	    Self::TimeExpired { subject } =>
+		write!(o,"Time of {} expired",subject),
+	    Self::TimeRequested { subject,duration,reason } =>
+		write!(o,"{} requests {}: {}",
+		       subject,Approx(*duration),reason),
+	    Self::BankChanged { subject,entry } =>
+		write!(o,"{} {} {} {} ({}), balance {}{}",
+		       entry.by,
+		       if entry.amount >= 0.0 { "credited" } else { "debited" },
+		       subject,
+		       Approx(entry.amount.abs()),
+		       entry.reason,
+		       if entry.balance < 0.0 { "-" } else { "" },
+		       Approx(entry.balance.abs()))
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope<T>(payload:T)->Envelope<T> {
+	Envelope { sender:Entity::Administrator("mom".to_string()),
+		   payload,
+		   signature:String::new() }
+    }
+
+    #[test]
+    fn cbor_nan_is_not_a_cancellation() {
+	let cmd = Command::Authorize { subject:"alice".to_string(),
+				       duration:Some(f64::NAN) };
+	let u = Encoding::Cbor.encode(&envelope(cmd)).unwrap();
+	match Encoding::Cbor.decode_command(&u).unwrap().payload {
+	    Command::Authorize { duration:Some(t),.. } => assert!(t.is_nan()),
+	    cmd => panic!("Decoded as {:?}",cmd)
+	}
+    }
+
+    #[test]
+    fn cbor_unknown_command_is_unsupported() {
+	let payload = serde_json::json!({ "Frobnicate":{ "subject":"alice" } });
+	let u = Encoding::Cbor.encode(&envelope(payload)).unwrap();
+	match Encoding::Cbor.decode_command(&u) {
+	    Err(DecodeError::Unsupported(tag)) => assert_eq!(tag,"Frobnicate"),
+	    res => panic!("Decoded as {:?}",res)
+	}
+	assert!(matches!(Encoding::Cbor.decode_command(b"\xff"),
+			 Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn unknown_error_code_is_decoded() {
+	let payload = serde_json::json!({
+	    "Error":{ "code":"Overheated","message":"Too hot" }
+	});
+	let u = serde_json::to_string(&envelope(payload)).unwrap();
+	match decode_response(&u).unwrap().payload {
+	    Response::Error { code,message } => {
+		assert_eq!(code,ErrorCode::Unknown);
+		assert_eq!(message,"Too hot");
+	    },
+	    resp => panic!("Decoded as {:?}",resp)
+	}
+    }
 }
@@ -0,0 +1,60 @@
+use std::{
+    io::IsTerminal,
+    str::FromStr
+};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    prelude::*
+};
+
+pub use tracing_subscriber::filter::LevelFilter as LogLevel;
+
+/// Output format of the diagnostics, which always go to standard
+/// error (or to the journal)
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+    Journald
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(u:&str)->Result<Self,String> {
+	match u {
+	    "text" => Ok(Self::Text),
+	    "json" => Ok(Self::Json),
+	    "journald" => Ok(Self::Journald),
+	    _ => Err(format!("Invalid log format {:?}, \
+			      expected text, json or journald",u))
+	}
+    }
+}
+
+pub const LOG_USAGE : &str =
+    "[--log-level error|warn|info|debug|trace] [--log-format text|json|journald]";
+
+pub fn init(level:LevelFilter,format:LogFormat)->std::io::Result<()> {
+    match format {
+	LogFormat::Text =>
+	    tracing_subscriber::fmt()
+	    .with_max_level(level)
+	    .with_ansi(std::io::stderr().is_terminal())
+	    .with_writer(std::io::stderr)
+	    .init(),
+	LogFormat::Json =>
+	    tracing_subscriber::fmt()
+	    .json()
+	    .with_max_level(level)
+	    .with_writer(std::io::stderr)
+	    .init(),
+	LogFormat::Journald => {
+	    let layer = tracing_journald::layer()?;
+	    tracing_subscriber::registry()
+		.with(layer.with_filter(level))
+		.init()
+	}
+    }
+    Ok(())
+}
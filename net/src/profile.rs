@@ -0,0 +1,200 @@
+//! Client configuration file: named profiles giving the server, the
+//! identity to use and its credentials, shared by the command-line
+//! client and the UI
+//!
+//! Connections are not encrypted, as TLS is not supported yet, so a
+//! key is only sent to a server on the loopback, e.g. the end of an
+//! SSH tunnel or of a TLS proxy such as stunnel.
+//!
+//! ```text
+//! (
+//!     default:Some("home"),
+//!     profiles:{
+//!         "home":(
+//!             url:"ws://127.0.0.1:9001/",
+//!             identity:Administrator("mom"),
+//!             key:Some("~/.config/discipline/mom.key"),
+//!             subject:Some("alice"),
+//!             kids:["alice","bob"]
+//!         )
+//!     }
+//! )
+//! ```
+
+use serde::Deserialize;
+use url::{
+    Host,
+    Url
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    os::unix::fs::PermissionsExt,
+    path::{
+	Path,
+	PathBuf
+    }
+};
+use crate::{
+    Entity,
+    MAX_DURATION
+};
+
+/// Signature sent when a profile has no key
+pub const NO_SIGNATURE : &str = "\\_'')_/";
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default = "Profile::default_url")]
+    pub url:String,
+    pub identity:Entity,
+    /// File holding the key or token sent as the signature of each
+    /// command; it must not be readable by others, and the URL must
+    /// be a loopback one
+    #[serde(default)]
+    pub key:Option<String>,
+    /// Subject of commands when none is given
+    #[serde(default)]
+    pub subject:Option<String>,
+    /// Subjects shown by the UI
+    #[serde(default)]
+    pub kids:Vec<String>,
+    #[serde(default = "Profile::default_retry_delay")]
+    pub retry_delay:f64,
+    #[serde(default = "Profile::default_loop_delay")]
+    pub loop_delay:f64
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profiles {
+    /// Profile used without --profile, the only one if not given
+    #[serde(default)]
+    pub default:Option<String>,
+    pub profiles:BTreeMap<String,Profile>
+}
+
+/// Whether the traffic to the URL stays on this machine
+fn is_loopback(url:&Url)->bool {
+    match url.host() {
+	Some(Host::Domain(name)) => name == "localhost",
+	Some(Host::Ipv4(a)) => a.is_loopback(),
+	Some(Host::Ipv6(a)) => a.is_loopback(),
+	None => false
+    }
+}
+
+/// Expand a leading ~/ to the home directory
+fn expand_home(path:&str)->PathBuf {
+    match (path.strip_prefix("~/"),std::env::var_os("HOME")) {
+	(Some(rest),Some(home)) => Path::new(&home).join(rest),
+	_ => PathBuf::from(path)
+    }
+}
+
+impl Profile {
+    fn default_url()->String {
+	"ws://127.0.0.1:9001".to_string()
+    }
+
+    fn default_retry_delay()->f64 {
+	5.0
+    }
+
+    fn default_loop_delay()->f64 {
+	10.0
+    }
+
+    pub fn validate(&self)->Result<(),String> {
+	let url = Url::parse(&self.url)
+	    .map_err(|e| format!("Invalid URL {:?}: {}",self.url,e))?;
+	match url.scheme() {
+	    "ws" => (),
+	    "wss" => return Err("TLS is not supported, use a ws:// URL \
+				 to a tunnel".to_string()),
+	    _ => return Err(format!("Invalid URL {:?}",self.url))
+	}
+	if self.key.is_some() && !is_loopback(&url) {
+	    return Err(format!("The key would be sent in clear to {:?}",
+			       self.url));
+	}
+	if let Entity::Controller = self.identity {
+	    return Err("Invalid identity".to_string());
+	}
+	for t in [self.retry_delay,self.loop_delay] {
+	    if !(t > 0.0 && t <= MAX_DURATION) {
+		return Err(format!("Invalid delay {}",t));
+	    }
+	}
+	Ok(())
+    }
+
+    /// Signature of commands sent to the URL: the contents of the key
+    /// file, which is refused if others can read it or if the URL is
+    /// not a loopback one
+    pub fn signature(&self,url:&Url)->Result<String,String> {
+	let Some(key) = &self.key else {
+	    return Ok(NO_SIGNATURE.to_string())
+	};
+	if !is_loopback(url) {
+	    return Err(format!("The key would be sent in clear to {}",url));
+	}
+	let path = expand_home(key);
+	let metadata = std::fs::metadata(&path)
+	    .map_err(|e| format!("Cannot read key file {:?}: {}",path,e))?;
+	if metadata.permissions().mode() & 0o077 != 0 {
+	    return Err(format!("Key file {:?} is accessible by others, \
+				use chmod 600",path));
+	}
+	let key = std::fs::read_to_string(&path)
+	    .map_err(|e| format!("Cannot read key file {:?}: {}",path,e))?;
+	let key = key.trim();
+	if key.is_empty() {
+	    return Err(format!("Key file {:?} is empty",path));
+	}
+	Ok(key.to_string())
+    }
+}
+
+impl Profiles {
+    /// $XDG_CONFIG_HOME/discipline/client.cfg, or under ~/.config
+    pub fn default_path()->PathBuf {
+	let dir = std::env::var_os("XDG_CONFIG_HOME")
+	    .filter(|d| !d.is_empty())
+	    .map(PathBuf::from)
+	    .unwrap_or_else(|| expand_home("~/.config"));
+	dir.join("discipline").join("client.cfg")
+    }
+
+    pub fn load<P:AsRef<Path>>(path:P)->Result<Self,String> {
+	let path = path.as_ref();
+	let fd = File::open(path)
+	    .map_err(|e| format!("Cannot open configuration file {:?}: {}",
+				 path,e))?;
+	let this : Self = ron::de::from_reader(BufReader::new(fd))
+	    .map_err(|e| format!("Cannot parse configuration file {:?}: {}",
+				 path,e))?;
+	for (name,profile) in this.profiles.iter() {
+	    profile.validate()
+		.map_err(|e| format!("Invalid profile {:?} in {:?}: {}",
+				     name,path,e))?;
+	}
+	Ok(this)
+    }
+
+    /// The given profile, or else the default one
+    pub fn get(&self,name:Option<&str>)->Result<&Profile,String> {
+	let name =
+	    match (name,&self.default) {
+		(Some(name),_) => name,
+		(None,Some(name)) => name.as_str(),
+		(None,None) if self.profiles.len() == 1 =>
+		    return Ok(self.profiles.values().next().unwrap()),
+		(None,None) => return Err("Specify a profile".to_string())
+	    };
+	self.profiles.get(name)
+	    .ok_or_else(|| format!("Unknown profile {:?}",name))
+    }
+}
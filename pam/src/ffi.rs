@@ -0,0 +1,56 @@
+//! The parts of the Linux-PAM interface used by the module
+//!
+//! The functions are not linked against libpam: they are resolved
+//! from the process that loads the module, which always has it.
+
+#![allow(non_camel_case_types)]
+
+use libc::{
+    c_char,
+    c_int,
+    c_void
+};
+
+pub const PAM_SUCCESS : c_int = 0;
+pub const PAM_SERVICE_ERR : c_int = 3;
+pub const PAM_PERM_DENIED : c_int = 6;
+pub const PAM_IGNORE : c_int = 25;
+
+pub const PAM_SILENT : c_int = 0x8000;
+
+pub const PAM_CONV : c_int = 5;
+
+pub const PAM_ERROR_MSG : c_int = 3;
+pub const PAM_TEXT_INFO : c_int = 4;
+
+pub enum pam_handle_t { }
+
+#[repr(C)]
+pub struct pam_message {
+    pub msg_style:c_int,
+    pub msg:*const c_char
+}
+
+#[repr(C)]
+pub struct pam_response {
+    pub resp:*mut c_char,
+    pub resp_retcode:c_int
+}
+
+#[repr(C)]
+pub struct pam_conv {
+    pub conv:Option<extern "C" fn(c_int,
+				  *mut *const pam_message,
+				  *mut *mut pam_response,
+				  *mut c_void)->c_int>,
+    pub appdata_ptr:*mut c_void
+}
+
+extern "C" {
+    pub fn pam_get_user(pamh:*mut pam_handle_t,
+			user:*mut *const c_char,
+			prompt:*const c_char)->c_int;
+    pub fn pam_get_item(pamh:*const pam_handle_t,
+			item_type:c_int,
+			item:*mut *const c_void)->c_int;
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    net::TcpStream,
+    path::{
+	Path,
+	PathBuf
+    },
+    str::FromStr,
+    time::{
+	Duration,
+	SystemTime,
+	UNIX_EPOCH
+    }
+};
+use serde::{
+    Deserialize,
+    Serialize
+};
+use url::Url;
+use tungstenite::{
+    client,
+    Message
+};
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+use discipline_net::*;
+
+/// What to do when the server cannot be reached
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Offline {
+    Allow,
+    Deny,
+    /// Allow if the last answer of the server left time until now
+    Cache
+}
+
+impl FromStr for Offline {
+    type Err = String;
+
+    fn from_str(u:&str)->Result<Self,String> {
+	match u {
+	    "allow" => Ok(Self::Allow),
+	    "deny" => Ok(Self::Deny),
+	    "cache" => Ok(Self::Cache),
+	    _ => Err(format!("Invalid offline policy {:?}, \
+			      expected allow, deny or cache",u))
+	}
+    }
+}
+
+/// Arguments of the module in the PAM configuration, e.g.
+///
+///     account requisite pam_discipline.so url=ws://server:9001/ \
+///         users=alice,bob=robert offline=cache
+pub struct Options {
+    pub url:Url,
+    /// Subject of each Unix user; other users are not checked
+    pub users:BTreeMap<String,String>,
+    pub cache_path:PathBuf,
+    pub offline:Offline,
+    /// Time allowed for the server to answer
+    pub timeout:f64
+}
+
+impl Options {
+    pub fn parse<'a,I:IntoIterator<Item=&'a str>>(args:I)->Result<Self> {
+	let mut url = None;
+	let mut users = BTreeMap::new();
+	let mut cache_path = PathBuf::from("/var/cache/discipline/pam.ron");
+	let mut offline = Offline::Cache;
+	let mut timeout = 3.0;
+	for arg in args {
+	    let Some((key,value)) = arg.split_once('=') else {
+		bail!("Invalid argument {:?}",arg)
+	    };
+	    match key {
+		"url" => url = Some(Url::parse(value)?),
+		"users" => {
+		    // USER or USER=SUBJECT
+		    for u in value.split(',') {
+			let (user,subject) = u.split_once('=').unwrap_or((u,u));
+			users.insert(user.to_string(),subject.to_string());
+		    }
+		},
+		"cache" => cache_path = value.into(),
+		"offline" => offline = value.parse().map_err(|e| anyhow!("{}",e))?,
+		"timeout" => timeout = value.parse()?,
+		_ => bail!("Unknown argument {:?}",key)
+	    }
+	}
+	if !(timeout > 0.0 && timeout <= 60.0) {
+	    bail!("Invalid timeout {}",timeout);
+	}
+	let url = url.ok_or_else(|| anyhow!("Missing url argument"))?;
+	Ok(Self { url,users,cache_path,offline,timeout })
+    }
+}
+
+/// Last answer of the server for a subject
+#[derive(Debug,Clone,Serialize,Deserialize)]
+struct CacheEntry {
+    checked:f64,
+    authorized_until:f64,
+    #[serde(default)]
+    enforcement:Enforcement
+}
+
+type Cache = BTreeMap<String,CacheEntry>;
+
+fn load_cache(path:&Path)->Cache {
+    File::open(path).ok()
+	.and_then(|fd| ron::de::from_reader(fd).ok())
+	.unwrap_or_default()
+}
+
+fn save_cache(path:&Path,cache:&Cache)->Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let fd = File::create(&tmp_path)?;
+    ron::ser::to_writer(fd,cache)?;
+    std::fs::rename(tmp_path,path)?;
+    Ok(())
+}
+
+pub enum Verdict {
+    Allow(Option<String>),
+    Deny(String)
+}
+
+fn now()->f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+	.map(|d| d.as_secs_f64())
+	.unwrap_or(0.0)
+}
+
+fn query(opts:&Options,subject:&str)->Result<(f64,Constraint,Enforcement)> {
+    let timeout = Duration::from_secs_f64(opts.timeout);
+    let addr = opts.url.socket_addrs(|| Some(9001))?
+	.into_iter().next()
+	.ok_or_else(|| anyhow!("Cannot resolve {}",opts.url))?;
+    let stream = TcpStream::connect_timeout(&addr,timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let (mut socket,_response) = client(opts.url.as_str(),stream)
+	.map_err(|e| anyhow!("Handshake failed: {}",e))?;
+    let mut transact = |payload:Command|->Result<Response> {
+	let cmd = Envelope {
+	    sender:Entity::Subject(subject.to_string()),
+	    signature:"\\_'')_/".to_string(),
+	    payload
+	};
+	socket.send(Message::Text(serde_json::to_string(&cmd)?))?;
+	match socket.read()? {
+	    Message::Text(u) => Ok(decode_response(&u)?.payload.into_result()?),
+	    _ => bail!("Invalid message type")
+	}
+    };
+    // Only text messages are read, so no CBOR
+    let features =
+	match transact(Command::Hello { version:PROTOCOL_VERSION,
+					features:Feature::ALL.iter().copied()
+					.filter(|f| *f != Feature::Cbor)
+					.collect() })? {
+	    Response::Welcome { features,.. } => features,
+	    _ => bail!("Unexpected response")
+	};
+    // Older servers count the query as a ping from the session
+    let subject = subject.to_string();
+    let cmd =
+	if features.contains(&Feature::LoginCheck) {
+	    Command::CheckLogin { subject }
+	} else {
+	    Command::GetStatus { subject }
+	};
+    match transact(cmd)? {
+	Response::Authorization { time_remaining,limited_by,enforcement,.. } =>
+	    Ok((time_remaining,limited_by,enforcement)),
+	_ => bail!("Unexpected response")
+    }
+}
+
+/// Decide on the login of a Unix user, None if the user is not a
+/// subject; errors are those of the cache, which do not change the
+/// verdict
+pub fn check(opts:&Options,user:&str)->Option<(Verdict,Result<()>)> {
+    let subject = opts.users.get(user)?;
+    let t_now = now();
+    let mut cache = load_cache(&opts.cache_path);
+    match query(opts,subject) {
+	Ok((time_remaining,limited_by,enforcement)) => {
+	    cache.insert(subject.clone(),CacheEntry {
+		checked:t_now,
+		authorized_until:t_now + time_remaining,
+		enforcement
+	    });
+	    let saved = save_cache(&opts.cache_path,&cache);
+	    let verdict =
+		if time_remaining > 0.0 {
+		    Verdict::Allow(Some(format!("{}, you have {} of computer \
+						 time left.",
+						user,Approx(time_remaining))))
+		} else if enforcement == Enforcement::Network {
+		    Verdict::Allow(Some(format!("{}, you have no time left, \
+						 the Internet is off.",user)))
+		} else if let Constraint::Curfew { to,.. } = limited_by {
+		    Verdict::Deny(format!("Sorry {}, it is curfew until {}.",
+					  user,to))
+		} else {
+		    Verdict::Deny(format!("Sorry {}, you have no computer time \
+					   left.  Ask a parent for more.",user))
+		};
+	    Some((verdict,saved))
+	},
+	Err(e) => {
+	    let unreachable = format!("Cannot reach the time server ({})",e);
+	    let verdict =
+		match opts.offline {
+		    Offline::Allow => Verdict::Allow(None),
+		    Offline::Deny => Verdict::Deny(unreachable),
+		    Offline::Cache =>
+			match cache.get(subject) {
+			    Some(entry) if entry.authorized_until > t_now ||
+				entry.enforcement == Enforcement::Network =>
+				Verdict::Allow(None),
+			    _ => Verdict::Deny(
+				format!("{}, and no time is known to be left.",
+					unreachable))
+			}
+		};
+	    Some((verdict,Ok(())))
+	}
+    }
+}
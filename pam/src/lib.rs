@@ -0,0 +1,113 @@
+//! PAM account module refusing logins of subjects that have no time
+//! left
+//!
+//! Built as `libpam_discipline.so`, to be installed as
+//! `pam_discipline.so` in the PAM module directory.
+
+mod ffi;
+mod gate;
+
+use std::ffi::{
+    CStr,
+    CString
+};
+use libc::{
+    c_char,
+    c_int,
+    c_void
+};
+use ffi::*;
+use gate::{
+    Options,
+    Verdict
+};
+
+fn syslog(priority:c_int,msg:&str) {
+    if let Ok(msg) = CString::new(format!("pam_discipline: {}",msg)) {
+	unsafe {
+	    libc::syslog(libc::LOG_AUTHPRIV | priority,
+			 c"%s".as_ptr(),msg.as_ptr());
+	}
+    }
+}
+
+/// Show a message to the user through the conversation function of
+/// the application
+fn say(pamh:*mut pam_handle_t,style:c_int,msg:&str) {
+    let Ok(msg) = CString::new(msg) else { return };
+    unsafe {
+	let mut item : *const c_void = std::ptr::null();
+	if pam_get_item(pamh,PAM_CONV,&mut item) != PAM_SUCCESS || item.is_null() {
+	    return;
+	}
+	let conv = &*(item as *const pam_conv);
+	let Some(f) = conv.conv else { return };
+	let message = pam_message { msg_style:style,msg:msg.as_ptr() };
+	let mut messages = [&message as *const pam_message];
+	let mut resp : *mut pam_response = std::ptr::null_mut();
+	f(1,messages.as_mut_ptr(),&mut resp,conv.appdata_ptr);
+	if !resp.is_null() {
+	    if !(*resp).resp.is_null() {
+		libc::free((*resp).resp as *mut c_void);
+	    }
+	    libc::free(resp as *mut c_void);
+	}
+    }
+}
+
+fn args<'a>(argc:c_int,argv:*const *const c_char)->Vec<&'a str> {
+    (0..argc.max(0) as usize)
+	.filter_map(|i| unsafe {
+	    let p = *argv.add(i);
+	    (!p.is_null()).then(|| CStr::from_ptr(p).to_str().ok()).flatten()
+	})
+	.collect()
+}
+
+/// # Safety
+///
+/// Called by libpam with a valid handle and argument vector.
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_acct_mgmt(pamh:*mut pam_handle_t,
+					  flags:c_int,
+					  argc:c_int,
+					  argv:*const *const c_char)->c_int {
+    let opts =
+	match Options::parse(args(argc,argv)) {
+	    Ok(opts) => opts,
+	    Err(e) => {
+		syslog(libc::LOG_ERR,&format!("Invalid configuration: {}",e));
+		return PAM_SERVICE_ERR;
+	    }
+	};
+    let mut user : *const c_char = std::ptr::null();
+    if pam_get_user(pamh,&mut user,std::ptr::null()) != PAM_SUCCESS
+	|| user.is_null() {
+	return PAM_SERVICE_ERR;
+    }
+    let Ok(user) = CStr::from_ptr(user).to_str() else {
+	return PAM_IGNORE;
+    };
+    let Some((verdict,saved)) = gate::check(&opts,user) else {
+	return PAM_IGNORE;
+    };
+    if let Err(e) = saved {
+	syslog(libc::LOG_WARNING,&format!("Cannot save cache: {}",e));
+    }
+    let silent = flags & PAM_SILENT != 0;
+    match verdict {
+	Verdict::Allow(msg) => {
+	    if let (Some(msg),false) = (msg,silent) {
+		say(pamh,PAM_TEXT_INFO,&msg);
+	    }
+	    PAM_SUCCESS
+	},
+	Verdict::Deny(msg) => {
+	    syslog(libc::LOG_NOTICE,&format!("Denying {}: {}",user,msg));
+	    if !silent {
+		say(pamh,PAM_ERROR_MSG,&msg);
+	    }
+	    PAM_PERM_DENIED
+	}
+    }
+}
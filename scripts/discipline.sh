@@ -21,6 +21,9 @@ fi
 
 t_alert_last=0
 
+NFT=${NFT:-nft}
+NFT_TABLE=discipline_$KID
+
 is_logged_in() {
     loginctl \
 	list-sessions -o json |
@@ -44,19 +47,79 @@ kick() {
     fi
 }
 
+network_blocked() {
+    $NFT list table inet $NFT_TABLE >/dev/null 2>&1
+}
+
+# Reject outbound traffic from the kid, except on the loopback
+# interface so that local services keep working
+
+block_network() {
+    if network_blocked ; then
+	return
+    fi
+    if [ $DRY_RUN = 1 ]; then
+	msg "Would be blocking network access for $KID_USER"
+    else
+	msg "Blocking network access for $KID_USER"
+	$NFT -f - <<EOF || msg "Cannot block network access"
+table inet $NFT_TABLE {
+    chain output {
+	type filter hook output priority 0; policy accept;
+	oifname "lo" accept
+	meta skuid $(id -u $KID_USER) reject
+    }
+}
+EOF
+    fi
+}
+
+unblock_network() {
+    if ! network_blocked ; then
+	return
+    fi
+    if [ $DRY_RUN = 1 ]; then
+	msg "Would be restoring network access for $KID_USER"
+    else
+	msg "Restoring network access for $KID_USER"
+	$NFT delete table inet $NFT_TABLE || msg "Cannot restore network access"
+    fi
+}
+
+# Play a sound file, then say the message given as second argument
+# if any
+
 sound_alert() {
     if [ $DRY_RUN = 1 ]; then
-	msg "Would be sounding alert $1"
+	msg "Would be sounding alert $1${2:+ and saying \"$2\"}"
     else
 	msg "Alert $1"
-	su $KID_USER -c "XDG_RUNTIME_DIR=/run/user/1000 aplay $1 >/dev/null 2>&1" || true &
+	{
+	    su $KID_USER -c "XDG_RUNTIME_DIR=/run/user/1000 aplay $1 >/dev/null 2>&1" || true
+	    if [ -n "$2" ]; then
+		su $KID_USER -c "XDG_RUNTIME_DIR=/run/user/1000 espeak-ng -v $SPEECH_LANGUAGE ${(q)2} >/dev/null 2>&1" || true
+	    fi
+	} &
     fi
 }
 
+# With SPEAK=1, the message of alert i is the i-th element of the
+# array ALERT_SPEECH_<language>, e.g. ALERT_SPEECH_en, where {name}
+# and {minutes} stand for the name of the kid and the minutes left
+
+SPEECH_LANGUAGE=${SPEECH_LANGUAGE:-en}
+
 alert() {
+    local speech=
+    local templates=ALERT_SPEECH_$SPEECH_LANGUAGE
     alert_file=$ALERT_PATH/${ALERT_FILES[$1]}
     alert_file=${alert_file:a}
-    sound_alert $alert_file
+    if [ "${SPEAK:-0}" = 1 ]; then
+	speech=${${(P)templates}[$1]}
+	speech=${speech//\{name\}/${KID_NAME:-$KID}}
+	speech=${speech//\{minutes\}/$(( (T + 59) / 60 ))}
+    fi
+    sound_alert $alert_file "$speech"
 }
 
 # Find the largest applicable alert index given the remaining time
@@ -81,12 +144,24 @@ find_alert_index() {
 
 last_alert_index=0
 
-$CLIENT --url $URL \
-	--sender-subject $KID \
-	--subject $KID \
+# With CLIENT_PROFILE, the server and credentials come from a profile
+# of the client configuration file (CLIENT_CONFIG if set)
+
+if [ -n "$CLIENT_PROFILE" ]; then
+    client_args=(--profile $CLIENT_PROFILE --subject $KID)
+    if [ -n "$CLIENT_CONFIG" ]; then
+	client_args+=(--config $CLIENT_CONFIG)
+    fi
+else
+    client_args=(--url $URL --sender-subject $KID --subject $KID)
+fi
+
+$CLIENT $client_args \
 	--show-time-remaining \
+	--show-enforcement \
 	--loop-delay $LOOP_DELAY \
-	--retry-delay $RETRY_DELAY | while read T ; do
+	--retry-delay $RETRY_DELAY \
+	--log-format ${LOG_FORMAT:-text} | while read T MODE ; do
     if [ -z "$T" ]; then
 	msg "Empty response"
 	continue
@@ -94,12 +169,18 @@ $CLIENT --url $URL \
     
     if [ $T = 0 ]; then
 	# Ran out of time
-	# Kick user
-	kick
+	if [ "$MODE" = network ]; then
+	    block_network
+	else
+	    unblock_network
+	    kick
+	fi
 	last_alert_index=0
 	continue
     fi
 
+    unblock_network
+
     if is_logged_in ; then
 	find_alert_index $T
 
@@ -111,3 +192,12 @@ $CLIENT --url $URL \
 	fi
     fi
 done
+client_status=${pipestatus[1]}
+
+# An unreachable server is retried by the client itself
+case $client_status in
+    0) ;;
+    10) fail "Subject $KID is unknown to the server" ;;
+    12) fail "Subject $KID is not allowed to query its status" ;;
+    *) fail "Client exited with status $client_status" ;;
+esac
@@ -0,0 +1,178 @@
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::BufReader
+};
+use chrono::{
+    Duration,
+    NaiveDate
+};
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+
+/// Inclusive range of dates
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DateRange {
+    pub from:NaiveDate,
+    pub to:NaiveDate
+}
+
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct CalendarConfig {
+    /// iCalendar file whose events are holidays, e.g. the school
+    /// calendar of the local authority
+    pub ics:Option<String>,
+    pub holidays:Vec<DateRange>
+}
+
+/// Days on which the holiday rules of the subjects apply
+#[derive(Debug,Clone,Default)]
+pub struct Calendar {
+    holidays:BTreeSet<NaiveDate>
+}
+
+impl Calendar {
+    /// Longest holiday taken from a single event, to catch broken
+    /// files
+    const MAX_DAYS : i64 = 366;
+
+    pub fn load(config:&CalendarConfig)->Result<Self> {
+	let mut this = Self::default();
+	for range in config.holidays.iter() {
+	    this.add(range.from,range.to)?;
+	}
+	if let Some(path) = &config.ics {
+	    this.load_ics(path)
+		.map_err(|e| anyhow!("Cannot load calendar {:?}: {}",path,e))?;
+	}
+	Ok(this)
+    }
+
+    fn add(&mut self,from:NaiveDate,to:NaiveDate)->Result<()> {
+	if to < from || (to - from).num_days() > Self::MAX_DAYS {
+	    bail!("Invalid holiday range {} to {}",from,to);
+	}
+	let mut date = from;
+	while date <= to {
+	    self.holidays.insert(date);
+	    date += Duration::days(1);
+	}
+	Ok(())
+    }
+
+    /// Add the all-day events of an iCalendar file; their DTEND is
+    /// exclusive
+    fn load_ics(&mut self,path:&str)->Result<()> {
+	let buf = BufReader::new(File::open(path)?);
+	for cal in ical::IcalParser::new(buf) {
+	    for event in cal?.events {
+		let date = |name:&str| {
+		    event.properties.iter()
+			.find(|p| p.name == name)
+			.and_then(|p| p.value.as_deref())
+			.map(parse_date)
+		};
+		let Some(from) = date("DTSTART") else {
+		    bail!("Event without DTSTART");
+		};
+		let from = from?;
+		let to =
+		    match date("DTEND") {
+			Some(to) => (to? - Duration::days(1)).max(from),
+			None => from
+		    };
+		self.add(from,to)?;
+	    }
+	}
+	Ok(())
+    }
+
+    pub fn is_holiday(&self,date:NaiveDate)->bool {
+	self.holidays.contains(&date)
+    }
+}
+
+/// Date part of an iCalendar DATE or DATE-TIME value
+fn parse_date(u:&str)->Result<NaiveDate> {
+    let day = u.get(0..8).ok_or_else(|| anyhow!("Invalid date {:?}",u))?;
+    NaiveDate::parse_from_str(day,"%Y%m%d")
+	.map_err(|e| anyhow!("Invalid date {:?}: {}",u,e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y:i32,m:u32,d:u32)->NaiveDate {
+	NaiveDate::from_ymd_opt(y,m,d).unwrap()
+    }
+
+    fn load_ics(name:&str,contents:&str)->Result<Calendar> {
+	let path = std::env::temp_dir()
+	    .join(format!("discipline-calendar-{}-{}.ics",std::process::id(),name));
+	std::fs::write(&path,contents).unwrap();
+	let config = CalendarConfig { ics:Some(path.to_str().unwrap().to_string()),
+				      holidays:Vec::new() };
+	let res = Calendar::load(&config);
+	std::fs::remove_file(&path).unwrap();
+	res
+    }
+
+    const HEADER : &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:test\r\n";
+
+    #[test]
+    fn ranges_are_inclusive() {
+	let config = CalendarConfig {
+	    ics:None,
+	    holidays:vec![DateRange { from:date(2026,12,19),to:date(2027,1,3) }]
+	};
+	let calendar = Calendar::load(&config).unwrap();
+	assert!(!calendar.is_holiday(date(2026,12,18)));
+	assert!(calendar.is_holiday(date(2026,12,19)));
+	assert!(calendar.is_holiday(date(2027,1,1)));
+	assert!(calendar.is_holiday(date(2027,1,3)));
+	assert!(!calendar.is_holiday(date(2027,1,4)));
+    }
+
+    #[test]
+    fn invalid_ranges_are_refused() {
+	let mut calendar = Calendar::default();
+	assert!(calendar.add(date(2026,5,2),date(2026,5,1)).is_err());
+	assert!(calendar.add(date(2026,1,1),date(2027,6,1)).is_err());
+    }
+
+    #[test]
+    fn ics_end_dates_are_exclusive() {
+	let calendar = load_ics("end",&format!(
+	    "{}BEGIN:VEVENT\r\nUID:1\r\nSUMMARY:Autumn\r\n\
+	     DTSTART;VALUE=DATE:20261017\r\nDTEND;VALUE=DATE:20261102\r\n\
+	     END:VEVENT\r\n\
+	     BEGIN:VEVENT\r\nUID:2\r\nSUMMARY:Christmas\r\n\
+	     DTSTART:20261225T000000Z\r\nEND:VEVENT\r\n\
+	     END:VCALENDAR\r\n",HEADER)).unwrap();
+	assert!(!calendar.is_holiday(date(2026,10,16)));
+	assert!(calendar.is_holiday(date(2026,10,17)));
+	assert!(calendar.is_holiday(date(2026,11,1)));
+	assert!(!calendar.is_holiday(date(2026,11,2)));
+	assert!(calendar.is_holiday(date(2026,12,25)));
+	assert!(!calendar.is_holiday(date(2026,12,26)));
+    }
+
+    #[test]
+    fn broken_ics_files_are_refused() {
+	assert!(load_ics("nostart",&format!(
+	    "{}BEGIN:VEVENT\r\nUID:1\r\nSUMMARY:Oops\r\nEND:VEVENT\r\n\
+	     END:VCALENDAR\r\n",HEADER)).is_err());
+	assert!(load_ics("baddate",&format!(
+	    "{}BEGIN:VEVENT\r\nUID:1\r\nDTSTART;VALUE=DATE:2026-10-17\r\n\
+	     END:VEVENT\r\nEND:VCALENDAR\r\n",HEADER)).is_err());
+	assert!(parse_date("2026").is_err());
+	assert_eq!(parse_date("20261017T080000").unwrap(),date(2026,10,17));
+    }
+}
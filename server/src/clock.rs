@@ -0,0 +1,167 @@
+use chrono::{
+    DateTime,
+    Duration,
+    LocalResult,
+    NaiveDate,
+    NaiveDateTime,
+    NaiveTime,
+    TimeZone,
+    Timelike
+};
+use chrono_tz::Tz;
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+use discipline_net::WallClock;
+
+/// Conversions between UNIX times and wall-clock times in the
+/// household time zone
+#[derive(Debug,Clone,Copy)]
+pub struct Clock {
+    tz:Tz
+}
+
+impl Clock {
+    pub fn new(name:&str)->Result<Self> {
+	let tz : Tz = name.parse()
+	    .map_err(|e| anyhow!("Invalid time zone {:?}: {}",name,e))?;
+	Ok(Self { tz })
+    }
+
+    /// Local time of a UNIX time, if it is within the range of
+    /// dates that can be represented
+    pub fn local(&self,t:f64)->Option<DateTime<Tz>> {
+	if !t.is_finite() {
+	    return None;
+	}
+	let secs = t.floor();
+	let nanos = ((t - secs) * 1e9) as u32;
+	self.tz.timestamp_opt(secs as i64,nanos).single()
+    }
+
+    /// Local time of a time taken from the system clock, such as the
+    /// current time, which is always in range
+    fn local_now(&self,t_now:f64)->DateTime<Tz> {
+	self.local(t_now).expect("System time out of range")
+    }
+
+    pub fn today(&self,t_now:f64)->NaiveDate {
+	self.local_now(t_now).date_naive()
+    }
+
+    pub fn wall_clock(&self,t_now:f64)->WallClock {
+	let local = self.local_now(t_now);
+	WallClock { hour:local.hour() as u8,minute:local.minute() as u8 }
+    }
+
+    /// UNIX time of the given wall-clock time on the given date.
+    /// When clocks go back, the earlier of the two instants is
+    /// used; when they go forward, times inside the gap map to the
+    /// end of the gap.
+    pub fn resolve(&self,date:NaiveDate,wc:WallClock)->Result<f64> {
+	let time = NaiveTime::from_hms_opt(wc.hour as u32,wc.minute as u32,0)
+	    .ok_or_else(|| anyhow!("Invalid time {}",wc))?;
+	let mut naive = NaiveDateTime::new(date,time);
+	// Gaps are at most a few hours long
+	for _ in 0..24*60 {
+	    match self.tz.from_local_datetime(&naive) {
+		LocalResult::Single(t) |
+		LocalResult::Ambiguous(t,_) =>
+		    return Ok(t.timestamp() as f64),
+		LocalResult::None =>
+		    naive += Duration::minutes(1)
+	    }
+	}
+	bail!("Cannot resolve {} on {} in {}",wc,date,self.tz)
+    }
+
+    /// UNIX time of the next occurrence of the given wall-clock
+    /// time, which is tomorrow if it has already passed today
+    pub fn next_occurrence(&self,t_now:f64,wc:WallClock)->Result<f64> {
+	let today = self.today(t_now);
+	let t = self.resolve(today,wc)?;
+	if t > t_now {
+	    return Ok(t);
+	}
+	let tomorrow = today.succ_opt()
+	    .ok_or_else(|| anyhow!("Date out of range"))?;
+	self.resolve(tomorrow,wc)
+    }
+
+    pub fn format(&self,t:f64)->Option<String> {
+	Some(self.local(t)?.format("%a %Y-%m-%d %H:%M %Z").to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn utc(y:i32,mo:u32,d:u32,h:u32,mi:u32)->f64 {
+	Utc.with_ymd_and_hms(y,mo,d,h,mi,0).unwrap().timestamp() as f64
+    }
+
+    fn wc(hour:u8,minute:u8)->WallClock {
+	WallClock { hour,minute }
+    }
+
+    fn paris()->Clock {
+	Clock::new("Europe/Paris").unwrap()
+    }
+
+    #[test]
+    fn spring_forward_gap_maps_to_its_end() {
+	// Clocks go from 02:00 to 03:00 CEST, i.e. 01:00 UTC
+	let date = NaiveDate::from_ymd_opt(2026,3,29).unwrap();
+	let end = utc(2026,3,29,1,0);
+	assert_eq!(paris().resolve(date,wc(2,30)).unwrap(),end);
+	assert_eq!(paris().resolve(date,wc(2,0)).unwrap(),end);
+	assert_eq!(paris().resolve(date,wc(3,0)).unwrap(),end);
+	assert_eq!(paris().resolve(date,wc(1,59)).unwrap(),end - 60.0);
+    }
+
+    #[test]
+    fn fall_back_overlap_takes_the_earlier_instant() {
+	// 02:30 happens at 00:30 UTC in CEST and again at 01:30 UTC in
+	// CET
+	let date = NaiveDate::from_ymd_opt(2026,10,25).unwrap();
+	assert_eq!(paris().resolve(date,wc(2,30)).unwrap(),
+		   utc(2026,10,25,0,30));
+    }
+
+    #[test]
+    fn until_rolls_over_to_tomorrow() {
+	// 23:00 CEST
+	let t_now = utc(2026,6,10,21,0);
+	let clock = paris();
+	assert_eq!(clock.next_occurrence(t_now,wc(1,0)).unwrap() - t_now,
+		   2.0*3600.0);
+	assert_eq!(clock.next_occurrence(t_now,wc(23,30)).unwrap() - t_now,
+		   1800.0);
+	// The current minute has already passed
+	assert_eq!(clock.next_occurrence(t_now,wc(23,0)).unwrap() - t_now,
+		   86400.0);
+    }
+
+    #[test]
+    fn times_out_of_range_are_not_formatted() {
+	let clock = paris();
+	assert_eq!(clock.format(utc(2026,6,10,21,0)).unwrap(),
+		   "Wed 2026-06-10 23:00 CEST");
+	for t in [1e300,-1e300,f64::NAN,f64::INFINITY] {
+	    assert!(clock.format(t).is_none());
+	}
+    }
+
+    #[test]
+    fn until_across_a_change_of_time() {
+	// 23:00 CET on the eve of the spring change: 07:00 is only 7h
+	// away
+	let t_now = utc(2026,3,28,22,0);
+	assert_eq!(paris().next_occurrence(t_now,wc(7,0)).unwrap() - t_now,
+		   7.0*3600.0);
+    }
+}
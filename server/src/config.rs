@@ -0,0 +1,159 @@
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path
+};
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+use url::Url;
+use crate::{
+    calendar::{
+	Calendar,
+	CalendarConfig
+    },
+    clock::Clock,
+    notify::{
+	NotifyConfig,
+	Sink
+    }
+};
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Interval between two checks for silent agents and expired
+    /// authorizations
+    pub interval:f64,
+    /// Number of missed ping intervals after which an agent is
+    /// considered silent
+    pub tolerance:f64
+}
+
+impl Default for WatchConfig {
+    fn default()->Self {
+	Self {
+	    interval:5.0,
+	    tolerance:3.0
+	}
+    }
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "Config::default_listen")]
+    pub listen:String,
+    #[serde(default = "Config::default_state_path")]
+    pub state_path:String,
+    /// IANA name of the household time zone, e.g. "Europe/Paris"
+    #[serde(default = "Config::default_timezone")]
+    pub timezone:String,
+    #[serde(default)]
+    pub watch:WatchConfig,
+    /// Holidays, on which the holiday rules of the subjects apply
+    #[serde(default)]
+    pub calendar:CalendarConfig,
+    #[serde(default)]
+    pub notify:NotifyConfig,
+    /// Subjects of the machine of each enforcement agent
+    #[serde(default)]
+    pub agents:BTreeMap<String,Vec<String>>
+}
+
+impl Default for Config {
+    fn default()->Self {
+	Self {
+	    listen:Self::default_listen(),
+	    state_path:Self::default_state_path(),
+	    timezone:Self::default_timezone(),
+	    watch:WatchConfig::default(),
+	    calendar:CalendarConfig::default(),
+	    notify:NotifyConfig::default(),
+	    agents:BTreeMap::new()
+	}
+    }
+}
+
+impl Config {
+    fn default_listen()->String {
+	"127.0.0.1:9001".to_string()
+    }
+
+    fn default_state_path()->String {
+	"state.dat".to_string()
+    }
+
+    fn default_timezone()->String {
+	"UTC".to_string()
+    }
+
+    pub fn load<P:AsRef<Path>>(path:P)->Result<Self> {
+	let path = path.as_ref();
+	let fd = File::open(path)
+	    .map_err(|e| anyhow!("Cannot open configuration file {:?}: {}",
+				 path,e))?;
+	let buf = BufReader::new(fd);
+	let this : Self = ron::de::from_reader(buf)
+	    .map_err(|e| anyhow!("Cannot parse configuration file {:?}: {}",
+				 path,e))?;
+	this.validate()
+	    .map_err(|e| anyhow!("Invalid configuration file {:?}: {}",
+				 path,e))?;
+	Ok(this)
+    }
+
+    pub fn validate(&self)->Result<()> {
+	self.listen.parse::<SocketAddr>()
+	    .map_err(|e| anyhow!("Invalid listen address {:?}: {}",
+				 self.listen,e))?;
+	if self.state_path.is_empty() {
+	    bail!("Empty state path");
+	}
+	Clock::new(&self.timezone)?;
+	Calendar::load(&self.calendar)?;
+	if !(self.watch.interval.is_finite() && self.watch.interval > 0.0) {
+	    bail!("Watch interval must be positive, got {}",
+		  self.watch.interval);
+	}
+	if !(self.watch.tolerance.is_finite() && self.watch.tolerance >= 1.0) {
+	    bail!("Watch tolerance must be at least 1, got {}",
+		  self.watch.tolerance);
+	}
+	for (name,subjects) in self.agents.iter() {
+	    if name.is_empty() || subjects.iter().any(|s| s.is_empty()) {
+		bail!("Empty agent or subject name in agents");
+	    }
+	}
+	if self.notify.queue_path.is_empty() {
+	    bail!("Empty notification queue path");
+	}
+	for (i,target) in self.notify.targets.iter().enumerate() {
+	    match &target.sink {
+		Sink::Webhook { url } => {
+		    Url::parse(url)
+			.map_err(|e| anyhow!("Notification target {}: \
+					      invalid webhook URL {:?}: {}",
+					     i,url,e))?;
+		},
+		Sink::Email(cfg) => {
+		    if cfg.to.is_empty() {
+			bail!("Notification target {}: no recipients",i);
+		    }
+		    for address in std::iter::once(&cfg.from).chain(cfg.to.iter()) {
+			address.parse::<lettre::message::Mailbox>()
+			    .map_err(|e| anyhow!("Notification target {}: \
+						  invalid address {:?}: {}",
+						 i,address,e))?;
+		    }
+		}
+	    }
+	}
+	Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+use chrono::{
+    Datelike,
+    Duration,
+    NaiveDate,
+    Weekday
+};
+use discipline_net::Hours;
+use crate::clock::Clock;
+
+/// Daily window during which a subject gets no computer time,
+/// whatever was granted
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Curfew {
+    /// Days on which the curfew starts, every day if empty; a
+    /// curfew from 21:30 to 07:00 on school nights starts on Sun,
+    /// Mon, Tue, Wed and Thu
+    #[serde(default)]
+    pub days:Vec<Weekday>,
+    pub hours:Hours
+}
+
+/// One occurrence of a curfew, as UNIX times
+#[derive(Clone,Debug)]
+pub struct Occurrence<'a> {
+    pub start:f64,
+    pub end:f64,
+    pub curfew:&'a Curfew
+}
+
+impl Curfew {
+    fn occurrence(&self,clock:&Clock,date:NaiveDate)
+		  ->Option<Occurrence<'_>> {
+	if !self.days.is_empty() && !self.days.contains(&date.weekday()) {
+	    return None;
+	}
+	let start = clock.resolve(date,self.hours.from).ok()?;
+	let end_date =
+	    if self.hours.to <= self.hours.from {
+		date + Duration::days(1)
+	    } else {
+		date
+	    };
+	let end = clock.resolve(end_date,self.hours.to).ok()?;
+	Some(Occurrence { start,end,curfew:self })
+    }
+}
+
+/// Occurrences of the curfews starting on the given date
+pub fn on<'a>(curfews:&'a [Curfew],
+	      clock:&'a Clock,
+	      date:NaiveDate)->impl Iterator<Item=Occurrence<'a>> {
+    curfews.iter().filter_map(move |c| c.occurrence(clock,date))
+}
+
+/// Curfew occurrence in force at the given time or, failing that,
+/// the next one to start within a week; `rules` gives the curfews
+/// applying to each date.  Occurrences starting at one of the
+/// `lifted` times are skipped.
+pub fn next<'a,F>(rules:F,
+		  clock:&'a Clock,
+		  t_now:f64,
+		  lifted:&[f64])->Option<Occurrence<'a>>
+where F:Fn(NaiveDate)->&'a [Curfew] {
+    let today = clock.today(t_now);
+    (-1..=7)
+	.map(|i| today + Duration::days(i))
+	.flat_map(|date| on(rules(date),clock,date))
+	.filter(|o| o.end > t_now && !lifted.contains(&o.start))
+	.min_by(|a,b| a.start.total_cmp(&b.start))
+}
@@ -1,4 +1,9 @@
 mod valve;
+mod notify;
+mod config;
+mod clock;
+mod curfew;
+mod calendar;
 
 use serde::{
     Deserialize,
@@ -6,6 +11,7 @@ use serde::{
 };
 use std::{
     time::{
+	Duration,
 	SystemTime,
 	UNIX_EPOCH
     },
@@ -21,37 +27,79 @@ use std::{
     collections::BTreeMap,
     sync::{
 	Arc,
-	Mutex
+	Mutex,
+	mpsc::{
+	    channel,
+	    Receiver
+	}
     },
     net::{
 	TcpListener,
 	TcpStream
     },
-    thread::spawn
+    thread::{
+	sleep,
+	spawn
+    },
+    io::ErrorKind
 };
 use tungstenite::{
     accept,
     Message
 };
 use pico_args::Arguments;
+use signal_hook::{
+    consts::SIGHUP,
+    iterator::Signals
+};
 use anyhow::{
     anyhow,
     bail,
     Result
 };
 use rand::Rng;
-use discipline_net::*;
+use tracing::{
+    debug,
+    error,
+    info,
+    warn
+};
+use discipline_net::{
+    *,
+    logging::{
+	LogFormat,
+	LogLevel,
+	LOG_USAGE
+    }
+};
+use chrono::NaiveDate;
 use valve::Valve;
-
-struct Config {
-    state_path:String
-}
+use notify::{
+    Notifier,
+    Sink,
+    Target
+};
+use config::Config;
+use clock::Clock;
+use curfew::Curfew;
+use calendar::Calendar;
 
 struct Controller {
     config:Config,
+    clock:Clock,
+    calendar:Calendar,
     state:ControllerState,
     serial:u64,
-    valve:Valve
+    valve:Valve,
+    notifier:Notifier
+}
+
+fn envelope(payload:Response)->Envelope<Response> {
+    Envelope {
+	sender:Entity::Controller,
+	payload,
+	signature:"\\_'')_/".to_string()
+    }
 }
 
 impl Controller {
@@ -64,40 +112,347 @@ impl Controller {
     }
     
     pub fn new(config:Config)->Result<Self> {
-	let state = ControllerState::load(&config.state_path)?;
+	let mut state = ControllerState::load(&config.state_path)?;
+	state.set_agents(config.agents.clone());
 	let serial = state.serial();
 	let valve = Valve::new(Self::SAVE_INTERVAL);
-	Ok(Self { config,state,serial,valve })
+	let notifier = Notifier::new(config.notify.clone())?;
+	let clock = Clock::new(&config.timezone)?;
+	let calendar = Calendar::load(&config.calendar)?;
+	Ok(Self { config,clock,calendar,state,serial,valve,notifier })
+    }
+
+    fn save(&mut self)->Result<()> {
+	let new_serial = self.state.serial();
+	if new_serial != self.serial {
+	    self.serial = new_serial;
+	    self.state.atomic_replace(&self.config.state_path)?;
+	}
+	Ok(())
+    }
+
+    fn dispatch(&mut self) {
+	for event in self.state.events.drain(..) {
+	    info!(subject = event.subject(),
+		  event = ?event.kind(),
+		  "{}",event);
+	    self.notifier.notify(event);
+	}
     }
 
     pub fn command(&mut self,env:Envelope<Command>)->Result<Envelope<Response>> {
-	let payload = self.state.handle(&env)?;
-	if let Some(_) = self.valve.tick() {
-	    let new_serial = self.state.serial();
-	    if new_serial != self.serial {
-		self.serial = new_serial;
-		self.state.atomic_replace(&self.config.state_path)?;
-	    }
+	let payload = self.state.handle(&self.clock,&self.calendar,&env)?;
+	self.dispatch();
+	if self.valve.tick().is_some() {
+	    self.save()?;
 	}
-	Ok(Envelope {
-	    sender:Entity::Controller,
-	    payload,
-	    signature:"\\_'')_/".to_string()
-	})
+	Ok(envelope(payload))
+    }
+
+    pub fn may_see(&self,sender:&Entity,subject:&str)->bool {
+	self.state.may_see(&self.clock,sender,subject,now())
+    }
+
+    pub fn subscribe(&mut self)->Receiver<Event> {
+	let (sender,receiver) = channel();
+	self.notifier.subscribe(sender);
+	receiver
+    }
+
+    pub fn reconfigure(&mut self,mut config:Config) {
+	if config.listen != self.config.listen {
+	    warn!(listen = self.config.listen,
+		  "Changing the listen address requires a restart");
+	    config.listen = self.config.listen.clone();
+	}
+	if config.state_path != self.config.state_path {
+	    warn!(state_path = self.config.state_path,
+		  "Changing the state path requires a restart");
+	    config.state_path = self.config.state_path.clone();
+	}
+	match Clock::new(&config.timezone) {
+	    Ok(clock) => self.clock = clock,
+	    Err(e) => error!(error = %e,"Keeping previous time zone")
+	}
+	match Calendar::load(&config.calendar) {
+	    Ok(calendar) => self.calendar = calendar,
+	    Err(e) => error!(error = %e,"Keeping previous calendar")
+	}
+	self.state.set_agents(config.agents.clone());
+	self.notifier.reconfigure(config.notify.clone());
+	config.notify.queue_path = self.config.notify.queue_path.clone();
+	self.config = config;
+    }
+
+    pub fn watch(&mut self)->Result<()> {
+	self.state.check(&self.clock,&self.calendar,now(),
+			 self.config.watch.tolerance);
+	self.dispatch();
+	self.save()
     }
 }
 
 #[derive(Clone,Debug,Serialize,Deserialize,)]
 struct SubjectInfo {
     last_ping:Option<f64>,
-    authorized_until:Option<f64>
+    authorized_until:Option<f64>,
+    /// Expected interval between two pings from the agent, if the
+    /// agent is to be watched
+    #[serde(default)]
+    ping_interval:Option<f64>,
+    /// Flag silence even when the subject has no authorization,
+    /// e.g. for machines that are expected to stay up
+    #[serde(default)]
+    watch_always:bool,
+    /// Time at which the agent was flagged as silent
+    #[serde(default)]
+    silent_since:Option<f64>,
+    /// Whether the expiry of the current authorization was notified
+    #[serde(default)]
+    expiry_reported:bool,
+    #[serde(default)]
+    policy:Policy,
+    /// What the agent does once the time is out
+    #[serde(default)]
+    enforcement:Enforcement,
+    /// Day (in the household time zone) of the last grant
+    #[serde(default)]
+    granted_day:Option<NaiveDate>,
+    /// Additional time granted on that day
+    #[serde(default)]
+    granted_today:f64,
+    /// Balance of the time bank
+    #[serde(default)]
+    bank:f64,
+    /// Every credit and debit of the time bank
+    #[serde(default)]
+    ledger:Vec<LedgerEntry>,
+    /// Curfews on school days, and on holidays unless
+    /// holiday_curfews is set
+    #[serde(default)]
+    curfews:Vec<Curfew>,
+    #[serde(default)]
+    holiday_curfews:Option<Vec<Curfew>>,
+    /// Starts of the curfew occurrences lifted by an administrator
+    #[serde(default)]
+    curfews_lifted:Vec<f64>,
+    /// Application categories with their daily limits, enforced by
+    /// the agent
+    #[serde(default)]
+    apps:Vec<AppCategory>,
+    /// Day (in the household time zone) of the usage below
+    #[serde(default)]
+    apps_day:Option<NaiveDate>,
+    /// Time spent today in each application category
+    #[serde(default)]
+    apps_used:BTreeMap<String,f64>,
+    /// Alerts sounded by the agent as the time runs out
+    #[serde(default)]
+    alerts:Vec<Alert>
 }
 
+/// Limits enforced on authorizations, whoever grants them
+#[derive(Clone,Debug,Default,Serialize,Deserialize,)]
+struct Policy {
+    /// Longest single authorization
+    #[serde(default)]
+    max_grant:Option<f64>,
+    /// Most additional time that can be granted in one day
+    #[serde(default)]
+    max_per_day:Option<f64>
+}
+
+impl SubjectInfo {
+    const SLACK : f64 = 1.0;
+
+    fn time_remaining(&self,t_now:f64)->f64 {
+	self.authorized_until.map(
+	    |t| (t - t_now).max(0.0)
+	).unwrap_or(0.0)
+    }
+
+    /// Curfews starting on the given date
+    fn curfews_on(&self,calendar:&Calendar,date:NaiveDate)->&[Curfew] {
+	match &self.holiday_curfews {
+	    Some(curfews) if calendar.is_holiday(date) => curfews,
+	    _ => &self.curfews
+	}
+    }
+
+    fn next_curfew<'a>(&'a self,clock:&'a Clock,calendar:&Calendar,t_now:f64)
+		       ->Option<curfew::Occurrence<'a>> {
+	curfew::next(|date| self.curfews_on(calendar,date),
+		     clock,t_now,&self.curfews_lifted)
+    }
+
+    /// Time remaining once cut short by curfews, and what ends it
+    fn effective_time_remaining(&self,clock:&Clock,calendar:&Calendar,
+				t_now:f64)->(f64,Constraint) {
+	let t = self.time_remaining(t_now);
+	match self.next_curfew(clock,calendar,t_now) {
+	    Some(o) if o.start < t_now + t => {
+		let Hours { from,to } = o.curfew.hours;
+		((o.start - t_now).max(0.0),Constraint::Curfew { from,to })
+	    },
+	    _ => (t,Constraint::Authorization)
+	}
+    }
+
+    fn status(&self,subject:&str,clock:&Clock,calendar:&Calendar,t_now:f64)
+	      ->Status {
+	let (time_remaining,limited_by) =
+	    self.effective_time_remaining(clock,calendar,t_now);
+	Status {
+	    subject:subject.to_string(),
+	    time_remaining,
+	    last_ping:self.last_ping.map(|t| t_now - t),
+	    bank:self.bank,
+	    limited_by,
+	    holiday:calendar.is_holiday(clock.today(t_now)),
+	    enforcement:self.enforcement
+	}
+    }
+
+    fn schedule(&self,clock:&Clock,calendar:&Calendar,t_now:f64)
+		->Vec<DaySchedule> {
+	let today = clock.today(t_now);
+	(0..7)
+	    .map(|i| today + chrono::Duration::days(i))
+	    .map(|date| DaySchedule {
+		date:date.to_string(),
+		holiday:calendar.is_holiday(date),
+		curfews:curfew::on(self.curfews_on(calendar,date),clock,date)
+		    .map(|o| Window {
+			start:o.start,
+			end:o.end,
+			description:format!("{} to {}",
+					    clock.format(o.start)
+					    .unwrap_or_default(),
+					    clock.format(o.end)
+					    .unwrap_or_default()),
+			lifted:self.curfews_lifted.contains(&o.start)
+		    })
+		    .collect()
+	    })
+	    .collect()
+    }
+
+    fn record_bank(&mut self,by:&str,amount:f64,reason:&str,t_now:f64)
+		   ->LedgerEntry {
+	self.bank += amount;
+	let entry = LedgerEntry {
+	    time:t_now,
+	    amount,
+	    balance:self.bank,
+	    by:by.to_string(),
+	    reason:reason.to_string()
+	};
+	self.ledger.push(entry.clone());
+	entry
+    }
+
+    /// Check an authorization for the given duration against the
+    /// policy and return the additional time it grants
+    fn check_grant(&self,today:NaiveDate,t_now:f64,duration:f64)
+		   ->std::result::Result<f64,String> {
+	let extra = (duration - self.time_remaining(t_now)).max(0.0);
+	self.check_policy(today,duration,extra)?;
+	Ok(extra)
+    }
+
+    /// Check a grant of the given size against the policy, extra
+    /// being what it adds to the time granted today
+    fn check_policy(&self,today:NaiveDate,size:f64,extra:f64)
+		    ->std::result::Result<(),String> {
+	if let Some(max) = self.policy.max_grant {
+	    if size > max {
+		return Err(format!("Authorization of {} exceeds the maximum \
+				    of {}",Approx(size),Approx(max)));
+	    }
+	}
+	if let Some(max) = self.policy.max_per_day {
+	    let granted =
+		if self.granted_day == Some(today) {
+		    self.granted_today
+		} else {
+		    0.0
+		};
+	    // Allow for the time elapsed since the remaining time was
+	    // displayed
+	    if granted + extra > max + Self::SLACK {
+		return Err(format!("Authorization would exceed the daily \
+				    maximum of {} ({} already granted)",
+				   Approx(max),Approx(granted)));
+	    }
+	}
+	Ok(())
+    }
+
+    fn record_usage(&mut self,today:NaiveDate,usage:&[AppUsage]) {
+	if self.apps_day != Some(today) {
+	    self.apps_day = Some(today);
+	    self.apps_used.clear();
+	}
+	// Unknown categories are ignored, lest clients fill the state
+	for AppUsage { category,seconds } in usage {
+	    if self.apps.iter().any(|c| &c.name == category) {
+		*self.apps_used.entry(category.clone()).or_insert(0.0) += seconds;
+	    }
+	}
+    }
+
+    fn app_budgets(&self,today:NaiveDate)->Vec<AppBudget> {
+	self.apps.iter()
+	    .map(|category| {
+		let used_today =
+		    if self.apps_day == Some(today) {
+			self.apps_used.get(&category.name).copied()
+			    .unwrap_or(0.0)
+		    } else {
+			0.0
+		    };
+		AppBudget {
+		    category:category.clone(),
+		    used_today,
+		    remaining:category.daily_limit
+			.map(|t| (t - used_today).max(0.0))
+		}
+	    })
+	    .collect()
+    }
+
+    fn record_grant(&mut self,today:NaiveDate,extra:f64) {
+	if self.granted_day != Some(today) {
+	    self.granted_day = Some(today);
+	    self.granted_today = 0.0;
+	}
+	self.granted_today += extra;
+    }
+}
+
+/// Scope of the administrators with full rights
+static FULL_SCOPE : Scope = Scope {
+    subjects:None,
+    max_grant:None,
+    hours:None,
+    expires:None
+};
+
 #[derive(Clone,Debug,Serialize,Deserialize,)]
 struct ControllerState {
     serial:u64,
     administrators:Vec<String>,
-    subjects:BTreeMap<String,SubjectInfo>
+    /// Administrators restricted to some subjects, grants, hours or
+    /// dates, e.g. a babysitter; guests added with AddGuest end up
+    /// here
+    #[serde(default)]
+    scoped_administrators:BTreeMap<String,Scope>,
+    /// Subjects of the machine of each agent, from the configuration
+    #[serde(skip)]
+    agents:BTreeMap<String,Vec<String>>,
+    subjects:BTreeMap<String,SubjectInfo>,
+    #[serde(skip)]
+    events:Vec<Event>
 }
 
 fn now()->f64 {
@@ -108,59 +463,302 @@ fn now()->f64 {
 }
 
 impl ControllerState {
+    fn set_agents(&mut self,agents:BTreeMap<String,Vec<String>>) {
+	for (name,subjects) in agents.iter() {
+	    for subject in subjects.iter() {
+		if !self.subjects.contains_key(subject) {
+		    warn!(agent = name,subject,"Agent of an unknown subject");
+		}
+	    }
+	}
+	self.agents = agents;
+    }
+
+    fn check(&mut self,clock:&Clock,calendar:&Calendar,t_now:f64,
+	     tolerance:f64) {
+	let mut updated = false;
+	for (subject,subject_info) in self.subjects.iter_mut() {
+	    let (time_remaining,_) =
+		subject_info.effective_time_remaining(clock,calendar,t_now);
+	    if !subject_info.expiry_reported &&
+		subject_info.authorized_until.is_some() &&
+		time_remaining <= 0.0 {
+		    subject_info.expiry_reported = true;
+		    self.events.push(Event::TimeExpired {
+			subject:subject.to_string()
+		    });
+		    updated = true;
+		}
+	    if subject_info.silent_since.is_some() {
+		continue;
+	    }
+	    let (Some(interval),Some(last_ping)) =
+		(subject_info.ping_interval,subject_info.last_ping)
+	    else {
+		continue;
+	    };
+	    let silent_for = t_now - last_ping;
+	    if silent_for > tolerance * interval &&
+		(subject_info.watch_always || time_remaining > 0.0) {
+		    subject_info.silent_since = Some(t_now);
+		    self.events.push(Event::AgentSilent {
+			subject:subject.to_string(),
+			silent_for
+		    });
+		    updated = true;
+		}
+	}
+	if updated {
+	    self.updated();
+	}
+    }
+
+    fn authorize(&mut self,
+		 clock:&Clock,
+		 sender:&Entity,
+		 subject:&str,
+		 duration:Option<f64>,
+		 t_now:f64)->Result<Response> {
+	let err = |code,u:&str| Ok(Response::error(code,u));
+
+	if let Some(Err(e)) = duration.map(check_duration) {
+	    return err(ErrorCode::InvalidArgument,&e);
+	}
+
+	let adm =
+	    match self.grantor(clock,sender,duration,t_now) {
+		Ok(adm) => adm,
+		Err(resp) => return Ok(resp)
+	    };
+	self.grant(clock,subject,&adm,duration,t_now)
+    }
+
+    /// Check that the sender is an administrator whose scope allows a
+    /// grant of the given size, and return its name
+    fn grantor(&self,clock:&Clock,sender:&Entity,size:Option<f64>,t_now:f64)
+	       ->std::result::Result<String,Response> {
+	let Entity::Administrator(adm) = sender else {
+	    return Err(Response::error(ErrorCode::PermissionDenied,
+				       "Only administrators can authorize"));
+	};
+	let scope = self.scope(clock,adm,t_now)?;
+	if let (Some(t),Some(max)) = (size,scope.max_grant) {
+	    if t > max {
+		return Err(Response::error(
+		    ErrorCode::LimitExceeded,
+		    format!("Authorization of {} exceeds the maximum \
+			     of {} allowed to {}",
+			    Approx(t),Approx(max),adm)));
+	    }
+	}
+	Ok(adm.clone())
+    }
+
+    /// Set the authorization of a subject, within its policy
+    fn grant(&mut self,
+	     clock:&Clock,
+	     subject:&str,
+	     by:&str,
+	     duration:Option<f64>,
+	     t_now:f64)->Result<Response> {
+	let err = |code,u:&str| Ok(Response::error(code,u));
+
+	let Some(subject_info) = self.subjects.get_mut(subject) else {
+	    return err(ErrorCode::UnknownSubject,
+		       &format!("Unknown subject {:?}",subject));
+	};
+	if let Some(t) = duration {
+	    let today = clock.today(t_now);
+	    match subject_info.check_grant(today,t_now,t) {
+		Ok(extra) => subject_info.record_grant(today,extra),
+		Err(e) => return err(ErrorCode::LimitExceeded,&e)
+	    }
+	}
+	self.set_authorization(subject,by,duration,t_now);
+	Ok(Response::Ack)
+    }
+
+    /// Add to the authorization of a subject; the policy applies to
+    /// the time added, not to the new total
+    fn add_time(&mut self,
+		clock:&Clock,
+		subject:&str,
+		by:&str,
+		amount:f64,
+		t_now:f64)->Result<Response> {
+	let Some(subject_info) = self.subjects.get_mut(subject) else {
+	    return Ok(Response::error(ErrorCode::UnknownSubject,
+				      format!("Unknown subject {:?}",subject)));
+	};
+	let total = subject_info.time_remaining(t_now) + amount;
+	if let Err(e) = check_duration(total) {
+	    return Ok(Response::error(ErrorCode::LimitExceeded,e));
+	}
+	let today = clock.today(t_now);
+	if let Err(e) = subject_info.check_policy(today,amount,amount) {
+	    return Ok(Response::error(ErrorCode::LimitExceeded,e));
+	}
+	subject_info.record_grant(today,amount);
+	self.set_authorization(subject,by,Some(total),t_now);
+	Ok(Response::Ack)
+    }
+
+    fn set_authorization(&mut self,subject:&str,by:&str,duration:Option<f64>,
+			 t_now:f64) {
+	let subject_info = self.subjects.get_mut(subject)
+	    .expect("Subject disappeared");
+	subject_info.authorized_until = duration.map(|t| t_now + t);
+	subject_info.expiry_reported = false;
+	self.events.push(Event::AuthorizationGranted {
+	    subject:subject.to_string(),
+	    administrator:by.to_string(),
+	    duration
+	});
+	self.updated();
+    }
+
+    /// Scope of an administrator, if it is in force
+    fn scope(&self,clock:&Clock,adm:&str,t_now:f64)
+	     ->std::result::Result<&Scope,Response> {
+	if self.administrators.iter().any(|a| a == adm) {
+	    return Ok(&FULL_SCOPE);
+	}
+	let Some(scope) = self.scoped_administrators.get(adm) else {
+	    return Err(Response::error(
+		ErrorCode::UnknownAdministrator,
+		format!("Unknown administrator {:?}",adm)));
+	};
+	scope.check(t_now,clock.wall_clock(t_now))
+	    .map_err(|e| Response::error(ErrorCode::PermissionDenied,e))?;
+	Ok(scope)
+    }
+
+    /// Whether the sender may see the subject: subjects only see
+    /// themselves and administrators the subjects in their scope
+    fn may_see(&self,clock:&Clock,sender:&Entity,subject:&str,t_now:f64)
+	       ->bool {
+	match sender {
+	    Entity::Administrator(adm) =>
+		self.scope(clock,adm,t_now)
+		.map(|s| s.covers(subject))
+		.unwrap_or(false),
+	    Entity::Subject(name) =>
+		name == subject && self.subjects.contains_key(name),
+	    Entity::Agent(name) =>
+		self.agents.get(name)
+		.map(|s| s.iter().any(|u| u == subject))
+		.unwrap_or(false) && self.subjects.contains_key(subject),
+	    Entity::Controller => false
+	}
+    }
+
+    /// Check that the sender may issue the command.  Refusals do not
+    /// depend on whether the target subject exists, so that names
+    /// cannot be probed.
+    fn permit(&self,
+	      clock:&Clock,
+	      sender:&Entity,
+	      cmd:&Command,
+	      t_now:f64)->std::result::Result<(),Response> {
+	match sender {
+	    Entity::Administrator(adm) => { self.scope(clock,adm,t_now)?; },
+	    Entity::Agent(name) if !self.agents.contains_key(name) =>
+		return Err(Response::error(ErrorCode::PermissionDenied,
+					   format!("Unknown agent {:?}",name))),
+	    _ => ()
+	}
+	let denied = |u:String| Err(Response::error(ErrorCode::PermissionDenied,u));
+	let see = |subject:&str| {
+	    if self.may_see(clock,sender,subject,t_now) {
+		Ok(())
+	    } else {
+		denied(format!("No access to subject {:?}",subject))
+	    }
+	};
+	let admin = matches!(sender,Entity::Administrator(_));
+	match cmd {
+	    Command::Hello { .. } |
+	    Command::GetAllStatus => Ok(()),
+	    Command::GetStatus { subject } |
+	    Command::CheckLogin { subject } |
+	    Command::ResolveUntil { subject,.. } => see(subject),
+	    Command::Authorize { subject,.. } |
+	    Command::AuthorizeUntil { subject,.. } |
+	    Command::Extend { subject,.. } if admin => see(subject),
+	    Command::Authorize { .. } |
+	    Command::AuthorizeUntil { .. } |
+	    Command::Extend { .. } =>
+		denied("Only administrators can authorize".to_string()),
+	    Command::Subscribe if admin => Ok(()),
+	    Command::Subscribe =>
+		denied("Only administrators can subscribe".to_string()),
+	    Command::RequestTime { subject,.. }
+	    if matches!(sender,Entity::Subject(name) if name == subject) =>
+		see(subject),
+	    Command::RequestTime { .. } =>
+		denied("Subjects can only request time for themselves"
+		       .to_string()),
+	    Command::AddGuest { .. } |
+	    Command::RemoveGuest { .. }
+	    if matches!(sender,Entity::Administrator(adm)
+			if self.administrators.contains(adm)) => Ok(()),
+	    Command::AddGuest { .. } |
+	    Command::RemoveGuest { .. } =>
+		denied("Only full administrators can manage guests"
+		       .to_string()),
+	    Command::Credit { subject,.. } |
+	    Command::Debit { subject,.. } if admin => see(subject),
+	    Command::Credit { .. } |
+	    Command::Debit { .. } =>
+		denied("Only administrators can credit or debit".to_string()),
+	    Command::Spend { subject,.. } |
+	    Command::GetLedger { subject } |
+	    Command::GetSchedule { subject } => see(subject),
+	    Command::LiftCurfew { subject } if admin => see(subject),
+	    Command::LiftCurfew { .. } =>
+		denied("Only administrators can lift curfews".to_string()),
+	    Command::ReportUsage { subject,.. } |
+	    Command::GetAlerts { subject } => see(subject),
+	    Command::GetStatuses { subjects } =>
+		subjects.iter().try_for_each(|subject| see(subject))
+	}
+    }
+
     fn handle(&mut self,
+	      clock:&Clock,
+	      calendar:&Calendar,
 	      env:&Envelope<Command>)->Result<Response> {
 	let t_now = now();
 	let mut updated = false;
 
-	if let Entity::Subject(subject) = &env.sender {
+	// An agent speaks for all the subjects of its machine, and a
+	// login attempt says nothing of whether a session is alive
+	let pinged =
+	    match &env.sender {
+		_ if matches!(env.payload,Command::CheckLogin { .. }) =>
+		    Vec::new(),
+		Entity::Subject(subject) => vec![subject.clone()],
+		Entity::Agent(name) =>
+		    self.agents.get(name).cloned().unwrap_or_default(),
+		_ => Vec::new()
+	    };
+	for subject in pinged {
 	    if let Some(subject_info) =
-		self.subjects.get_mut(subject) {
+		self.subjects.get_mut(&subject) {
 		    subject_info.last_ping = Some(t_now);
+		    if subject_info.silent_since.take().is_some() {
+			self.events.push(Event::AgentResumed {
+			    subject
+			});
+		    }
 		    updated = true;
 		}
 	}
 
-	let err = |u:&str| Ok(Response::Error(u.to_string()));
-
 	let resp =
-	    match &env.payload {
-		Command::GetStatus { subject } => {
-		    if let Some(subject_info) =
-			self.subjects.get(subject) {
-			    let time_remaining =
-				subject_info.authorized_until.map(
-				    |t| (t - t_now).max(0.0)
-				).unwrap_or(0.0);
-			    Ok(Response::Authorization {
-				subject:subject.to_string(),
-				last_ping:subject_info.last_ping
-				    .map(|t| t_now - t),
-				time_remaining
-			    })
-			} else {
-			    err(&format!("Unknown subject {:?}",subject))
-			}
-		},
-		Command::Authorize { subject,duration } => {
-		    if let Entity::Administrator(adm) = &env.sender {
-			if self.administrators.contains(adm) {
-			    if let Some(subject_info) =
-				self.subjects.get_mut(subject) {
-				    subject_info.authorized_until =
-					duration.map(|t| t_now + t);
-				    updated = true;
-				    Ok(Response::Ack)
-				} else {
-				    err(&format!("Unknown subject {:?}",subject))
-				}
-			} else {
-			    err(&format!("Unknown administrator {:?}",adm))
-			}
-		    } else {
-			err("Only administrators can authorize")
-		    }
-		}
+	    match self.permit(clock,&env.sender,&env.payload,t_now) {
+		Ok(()) => self.execute(clock,calendar,env,t_now),
+		Err(resp) => Ok(resp)
 	    };
 
 	if updated {
@@ -169,6 +767,306 @@ impl ControllerState {
 
 	resp
     }
+
+    fn execute(&mut self,
+	       clock:&Clock,
+	       calendar:&Calendar,
+	       env:&Envelope<Command>,
+	       t_now:f64)->Result<Response> {
+	let err = |code,u:&str| Ok(Response::error(code,u));
+
+	match &env.payload {
+	    Command::GetStatus { subject } |
+	    Command::CheckLogin { subject } => {
+		if let Some(subject_info) =
+		    self.subjects.get(subject) {
+			let Status { subject,time_remaining,last_ping,bank,
+				     limited_by,holiday,enforcement } =
+			    subject_info.status(subject,clock,calendar,t_now);
+			Ok(Response::Authorization {
+			    subject,
+			    time_remaining,
+			    last_ping,
+			    bank,
+			    limited_by,
+			    holiday,
+			    enforcement
+			})
+		    } else {
+			err(ErrorCode::UnknownSubject,
+			    &format!("Unknown subject {:?}",subject))
+		    }
+	    },
+	    Command::GetAllStatus => {
+		let statuses =
+		    self.subjects.iter()
+		    .filter(|(subject,_)|
+			    self.may_see(clock,&env.sender,subject,t_now))
+		    .map(|(subject,info)|
+			 info.status(subject,clock,calendar,t_now))
+		    .collect();
+		Ok(Response::Statuses(statuses))
+	    },
+	    Command::GetStatuses { subjects } => {
+		let mut statuses = Vec::new();
+		for subject in subjects {
+		    match self.subjects.get(subject) {
+			Some(info) =>
+			    statuses.push(info.status(subject,clock,calendar,t_now)),
+			None => return err(ErrorCode::UnknownSubject,
+					   &format!("Unknown subject {:?}",subject))
+		    }
+		}
+		Ok(Response::Statuses(statuses))
+	    },
+	    Command::Authorize { subject,duration } =>
+		self.authorize(clock,&env.sender,subject,*duration,t_now),
+	    Command::AuthorizeUntil { subject,local_time } => {
+		match clock.next_occurrence(t_now,*local_time) {
+		    Ok(t) => self.authorize(clock,&env.sender,subject,
+					    Some(t - t_now),t_now),
+		    Err(e) => err(ErrorCode::InvalidArgument,&e.to_string())
+		}
+	    },
+	    Command::Extend { subject,duration } => {
+		// Added to the authorization itself, not to what is left
+		// of it before a curfew; the limits apply to the time
+		// added, like for Spend
+		if let Err(e) = check_duration(*duration) {
+		    return err(ErrorCode::InvalidArgument,&e);
+		}
+		match self.grantor(clock,&env.sender,Some(*duration),t_now) {
+		    Ok(adm) => self.add_time(clock,subject,&adm,*duration,t_now),
+		    Err(resp) => Ok(resp)
+		}
+	    },
+	    Command::ResolveUntil { subject,local_time } => {
+		match clock.next_occurrence(t_now,*local_time) {
+		    Ok(t) => Ok(Response::Resolved {
+			subject:subject.to_string(),
+			local_time:*local_time,
+			until:t,
+			description:clock.format(t).unwrap_or_default()
+		    }),
+		    Err(e) => err(ErrorCode::InvalidArgument,&e.to_string())
+		}
+	    },
+	    Command::Hello { .. } =>
+		err(ErrorCode::InvalidRequest,
+		    "Handshake is handled by the connection"),
+	    Command::Subscribe => Ok(Response::Ack),
+	    Command::RequestTime { subject,duration,reason } => {
+		if check_duration(*duration).is_err() {
+		    return err(ErrorCode::InvalidArgument,
+			       &format!("Invalid duration {}",duration));
+		}
+		self.events.push(Event::TimeRequested {
+		    subject:subject.to_string(),
+		    duration:*duration,
+		    reason:reason.to_string()
+		});
+		Ok(Response::Ack)
+	    },
+	    Command::AddGuest { name,scope } => {
+		if let Err(e) = self.check_guest(name,scope,t_now) {
+		    return err(ErrorCode::InvalidArgument,&e);
+		}
+		info!(guest = name,
+		      subjects = ?scope.subjects,
+		      max_grant = scope.max_grant,
+		      hours = scope.hours.map(|h| h.to_string()),
+		      expires = scope.expires.and_then(|t| clock.format(t)),
+		      "Adding guest");
+		self.scoped_administrators.insert(name.to_string(),scope.clone());
+		self.updated();
+		Ok(Response::Ack)
+	    },
+	    Command::Credit { subject,amount,reason } |
+	    Command::Debit { subject,amount,reason } => {
+		if let Err(e) = check_duration(*amount) {
+		    return err(ErrorCode::InvalidArgument,&e);
+		}
+		if reason.trim().is_empty() {
+		    return err(ErrorCode::InvalidArgument,"A reason is required");
+		}
+		if let (Entity::Administrator(adm),Command::Credit { .. }) =
+		    (&env.sender,&env.payload) {
+			let max_grant =
+			    self.scope(clock,adm,t_now).ok()
+			    .and_then(|scope| scope.max_grant);
+			if let Some(max) = max_grant {
+			    if *amount > max {
+				return err(ErrorCode::LimitExceeded,
+					   &format!("Credit of {} exceeds the \
+						     maximum of {} allowed to {}",
+						    Approx(*amount),Approx(max),
+						    adm));
+			    }
+			}
+		    }
+		let amount =
+		    if matches!(env.payload,Command::Debit { .. }) {
+			-amount
+		    } else {
+			*amount
+		    };
+		let Some(subject_info) = self.subjects.get_mut(subject) else {
+		    return err(ErrorCode::UnknownSubject,
+			       &format!("Unknown subject {:?}",subject));
+		};
+		let entry = subject_info.record_bank(&env.sender.to_string(),
+						     amount,reason,t_now);
+		info!(subject,by = entry.by,amount,balance = entry.balance,
+		      reason,"Bank");
+		self.events.push(Event::BankChanged {
+		    subject:subject.to_string(),
+		    entry
+		});
+		self.updated();
+		Ok(Response::Ack)
+	    },
+	    Command::Spend { subject,amount } => {
+		if let Err(e) = check_duration(*amount) {
+		    return err(ErrorCode::InvalidArgument,&e);
+		}
+		let Some(subject_info) = self.subjects.get(subject) else {
+		    return err(ErrorCode::UnknownSubject,
+			       &format!("Unknown subject {:?}",subject));
+		};
+		if *amount > subject_info.bank {
+		    return err(ErrorCode::LimitExceeded,
+			       &format!("Only {} in the bank",
+					Approx(subject_info.bank.max(0.0))));
+		}
+		let by = env.sender.to_string();
+		let resp = self.add_time(clock,subject,&by,*amount,t_now)?;
+		if !matches!(resp,Response::Ack) {
+		    return Ok(resp);
+		}
+		let subject_info = self.subjects.get_mut(subject)
+		    .expect("Subject disappeared");
+		let entry = subject_info.record_bank(&by,-amount,"Spent",t_now);
+		info!(subject,by = entry.by,amount = -amount,
+		      balance = entry.balance,"Bank");
+		self.events.push(Event::BankChanged {
+		    subject:subject.to_string(),
+		    entry
+		});
+		Ok(Response::Ack)
+	    },
+	    Command::LiftCurfew { subject } => {
+		let Some(subject_info) = self.subjects.get_mut(subject) else {
+		    return err(ErrorCode::UnknownSubject,
+			       &format!("Unknown subject {:?}",subject));
+		};
+		let start = subject_info.next_curfew(clock,calendar,t_now)
+		    .map(|o| o.start);
+		let Some(start) = start else {
+		    return err(ErrorCode::InvalidArgument,
+			       "No curfew in the coming week");
+		};
+		info!(subject,by = %env.sender,start = clock.format(start),
+		      "Lifting curfew");
+		// Occurrences that started more than a day ago are over
+		subject_info.curfews_lifted.retain(|t| *t > t_now - 86400.0);
+		subject_info.curfews_lifted.push(start);
+		subject_info.expiry_reported = false;
+		self.updated();
+		Ok(Response::Ack)
+	    },
+	    Command::ReportUsage { subject,usage } => {
+		for AppUsage { seconds,.. } in usage {
+		    if let Err(e) = check_duration(*seconds) {
+			return err(ErrorCode::InvalidArgument,&e);
+		    }
+		}
+		let Some(subject_info) = self.subjects.get_mut(subject) else {
+		    return err(ErrorCode::UnknownSubject,
+			       &format!("Unknown subject {:?}",subject));
+		};
+		let today = clock.today(t_now);
+		subject_info.record_usage(today,usage);
+		let budgets = subject_info.app_budgets(today);
+		if !usage.is_empty() {
+		    debug!(subject,?usage,"Application usage");
+		    self.updated();
+		}
+		Ok(Response::AppBudgets {
+		    subject:subject.to_string(),
+		    budgets
+		})
+	    },
+	    Command::GetSchedule { subject } => {
+		match self.subjects.get(subject) {
+		    Some(subject_info) => Ok(Response::Schedule {
+			subject:subject.to_string(),
+			days:subject_info.schedule(clock,calendar,t_now)
+		    }),
+		    None => err(ErrorCode::UnknownSubject,
+				&format!("Unknown subject {:?}",subject))
+		}
+	    },
+	    Command::GetAlerts { subject } => {
+		match self.subjects.get(subject) {
+		    Some(subject_info) => Ok(Response::Alerts {
+			subject:subject.to_string(),
+			alerts:subject_info.alerts.clone()
+		    }),
+		    None => err(ErrorCode::UnknownSubject,
+				&format!("Unknown subject {:?}",subject))
+		}
+	    },
+	    Command::GetLedger { subject } => {
+		match self.subjects.get(subject) {
+		    Some(subject_info) => Ok(Response::Ledger {
+			subject:subject.to_string(),
+			balance:subject_info.bank,
+			entries:subject_info.ledger.clone()
+		    }),
+		    None => err(ErrorCode::UnknownSubject,
+				&format!("Unknown subject {:?}",subject))
+		}
+	    },
+	    Command::RemoveGuest { name } => {
+		if self.scoped_administrators.remove(name).is_some() {
+		    info!(guest = name,"Removing guest");
+		    self.updated();
+		    Ok(Response::Ack)
+		} else {
+		    err(ErrorCode::UnknownAdministrator,
+			&format!("Unknown guest {:?}",name))
+		}
+	    }
+	}
+    }
+
+    fn check_guest(&self,name:&str,scope:&Scope,t_now:f64)
+		   ->std::result::Result<(),String> {
+	if name.is_empty() {
+	    return Err("Empty guest name".to_string());
+	}
+	if self.administrators.iter().any(|a| a == name) {
+	    return Err(format!("{:?} is already a full administrator",name));
+	}
+	match scope.expires {
+	    None => return Err("Guests must have an expiry".to_string()),
+	    Some(t) if !t.is_finite() || t <= t_now =>
+		return Err("Expiry is in the past".to_string()),
+	    Some(t) if t > t_now + MAX_DURATION =>
+		return Err(format!("Expiry is more than {} away",
+				   Approx(MAX_DURATION))),
+	    _ => ()
+	}
+	if let Some(t) = scope.max_grant {
+	    check_duration(t)?;
+	}
+	for subject in scope.subjects.iter().flatten() {
+	    if !self.subjects.contains_key(subject) {
+		return Err(format!("Unknown subject {:?}",subject));
+	    }
+	}
+	Ok(())
+    }
 }
 
 pub trait Updateable where Self:Sized {
@@ -204,14 +1102,27 @@ impl Updateable for ControllerState {
 	Self {
 	    serial:0,
 	    administrators:Vec::new(),
-	    subjects:BTreeMap::new()
+	    scoped_administrators:BTreeMap::new(),
+	    agents:BTreeMap::new(),
+	    subjects:BTreeMap::new(),
+	    events:Vec::new()
 	}
     }
 
     fn load<P:AsRef<Path>>(path:P)->Result<Self> {
 	let fd = File::open(path)?;
 	let buf = BufReader::new(fd);
-	Ok(ron::de::from_reader(buf)?)
+	let this : Self = ron::de::from_reader(buf)?;
+	// The agents play what they are given, so refuse bad alerts here
+	// rather than have them fail when time runs out
+	for (subject,info) in this.subjects.iter() {
+	    for alert in info.alerts.iter() {
+		alert.check()
+		    .map_err(|e| anyhow!("Invalid alert of {:?} at {}: {}",
+					 subject,alert.before,e))?;
+	    }
+	}
+	Ok(this)
     }
     
     fn save<P:AsRef<Path>>(&self,path:P)->Result<()> {
@@ -229,57 +1140,208 @@ impl Updateable for ControllerState {
     }
 }
 
+/// Protocol state of a connection
+struct Session {
+    /// Negotiated protocol version, 1 until the peer says hello
+    version:u32,
+    /// Features announced by the peer
+    features:Vec<Feature>,
+    /// Encoding of the last message from the peer, used for the
+    /// replies and the events
+    encoding:Encoding,
+    /// Subscriber and its events
+    events:Option<(Entity,Receiver<Event>)>
+}
+
+impl Session {
+    fn new()->Self {
+	Self {
+	    version:1,
+	    features:Vec::new(),
+	    encoding:Encoding::Json,
+	    events:None
+	}
+    }
+
+    /// Whether the peer can decode the event: those added after the
+    /// handshake are only sent to peers announcing their feature
+    fn understands(&self,event:&Event)->bool {
+	event.feature().is_none_or(|f| self.features.contains(&f))
+    }
+
+    fn encode(&self,resp:&Envelope<Response>)->Result<Message> {
+	if self.encoding == Encoding::Cbor {
+	    let v = self.encoding.encode(resp).map_err(|e| anyhow!(e))?;
+	    return Ok(Message::Binary(v));
+	}
+	if self.version >= 2 {
+	    return Ok(Message::Text(serde_json::to_string(resp)?));
+	}
+	// Version 1 wrapped envelopes in a Result, reported malformed
+	// requests as Err and had plain error strings
+	let v =
+	    match &resp.payload {
+		Response::Error { code:ErrorCode::InvalidRequest |
+				  ErrorCode::Unsupported,message } =>
+		    serde_json::json!({ "Err":message }),
+		Response::Error { message,.. } => {
+		    let mut env = serde_json::to_value(resp)?;
+		    env["payload"] = serde_json::json!({ "Error":message });
+		    serde_json::json!({ "Ok":env })
+		},
+		_ => serde_json::json!({ "Ok":resp })
+	    };
+	Ok(Message::Text(serde_json::to_string(&v)?))
+    }
+}
+
 struct ApiServer {
     ctl:Arc<Mutex<Controller>>,
     server:TcpListener
 }
 
 impl ApiServer {
-    pub fn new(listen_addr:&str,config:Config)->Result<Self> {
+    pub fn new(config:Config)->Result<Self> {
+	let server = TcpListener::bind(&config.listen)?;
 	let ctl = Arc::new(Mutex::new(Controller::new(config)?));
-	let server = TcpListener::bind(listen_addr)?;
 	Ok(Self {
 	    ctl,
 	    server
 	})
     }
 
+    const POLL_INTERVAL : f64 = 0.25;
+
     fn handle_message(ctl:&Arc<Mutex<Controller>>,
-		      msg:&Message)->Result<Envelope<Response>> {
-	match msg {
-	    Message::Text(u) => {
-		let cmd : Envelope<Command> = serde_json::from_str(&u)
-		    .map_err(|e| anyhow!("Invalid JSON: {}",e))?;
-		ctl.lock().unwrap().command(cmd)
-	    },
-	    _ => bail!("Invalid message type")
+		      peer:&str,
+		      msg:&Message,
+		      session:&mut Session)
+		      ->Envelope<Response> {
+	let invalid = |code:ErrorCode,message:String| {
+	    warn!(peer,error = message,"Invalid message");
+	    envelope(Response::error(code,message))
+	};
+	let (encoding,u) =
+	    match msg {
+		Message::Text(u) => (Encoding::Json,u.as_bytes()),
+		Message::Binary(u) => (Encoding::Cbor,&u[..]),
+		_ => return invalid(ErrorCode::InvalidRequest,
+				    "Invalid message type".to_string())
+	    };
+	session.encoding = encoding;
+	let cmd =
+	    match encoding.decode_command(u) {
+		Ok(cmd) => cmd,
+		Err(e) => return invalid(e.code(),e.to_string())
+	    };
+	if let Command::Hello { version,features } = &cmd.payload {
+	    session.version = (*version).clamp(1,PROTOCOL_VERSION);
+	    session.features = features.clone();
+	    debug!(peer,sender = %cmd.sender,version,
+		   features = ?features,
+		   negotiated = session.version,
+		   "Hello");
+	    return envelope(Response::Welcome {
+		version:session.version,
+		features:Feature::ALL.to_vec()
+	    });
+	}
+	let subscriber = matches!(cmd.payload,Command::Subscribe)
+	    .then(|| cmd.sender.clone());
+	let sender = cmd.sender.to_string();
+	let command = cmd.payload.name();
+	let subject = cmd.payload.subject().map(|u| u.to_string());
+	let mut ctl = ctl.lock().unwrap();
+	let resp =
+	    match ctl.command(cmd) {
+		Ok(resp) => resp,
+		Err(e) => {
+		    error!(peer,sender,command,subject,error = %e,
+			   "Command failed");
+		    return envelope(Response::error(ErrorCode::Internal,
+						    e.to_string()));
+		}
+	    };
+	if let Response::Error { code,message } = &resp.payload {
+	    warn!(peer,sender,command,subject,code = ?code,error = message,
+		  "Command refused");
+	} else {
+	    debug!(peer,sender,command,subject,"Command");
 	}
+	if let Some(subscriber) = subscriber {
+	    if matches!(resp.payload,Response::Ack) &&
+		session.events.is_none() {
+		    session.events = Some((subscriber,ctl.subscribe()));
+		}
+	}
+	resp
     }
     
-    fn handle(ctl:Arc<Mutex<Controller>>,stream:TcpStream)->Result<()> {
+    fn handle(ctl:Arc<Mutex<Controller>>,
+	      peer:&str,
+	      stream:TcpStream)->Result<()> {
 	let mut websocket = accept(stream)?;
+	debug!(peer,"Connected");
+	let mut session = Session::new();
 	loop {
-	    let msg = websocket.read()?;
+	    if let Some((subscriber,receiver)) = &session.events {
+		while let Ok(event) = receiver.try_recv() {
+		    if !session.understands(&event) ||
+			!ctl.lock().unwrap().may_see(subscriber,event.subject()) {
+			continue;
+		    }
+		    let response = envelope(Response::Event(event));
+		    websocket.send(session.encode(&response)?)?;
+		}
+	    }
+	    let msg =
+		match websocket.read() {
+		    Ok(msg) => msg,
+		    Err(tungstenite::Error::Io(e))
+			if e.kind() == ErrorKind::WouldBlock ||
+			e.kind() == ErrorKind::TimedOut => continue,
+		    Err(e) => return Err(e.into())
+		};
 	    if msg.is_close() {
+		debug!(peer,"Disconnected");
 		break;
 	    }
-	    let response =
-		Self::handle_message(&ctl,&msg)
-		.map_err(|e| format!("{}",e));
-	    let v = serde_json::to_string(&response)?;
-	    websocket.send(Message::Text(v))?;
+	    if msg.is_ping() || msg.is_pong() {
+		continue;
+	    }
+	    let subscribed = session.events.is_some();
+	    let response = Self::handle_message(&ctl,peer,&msg,&mut session);
+	    websocket.send(session.encode(&response)?)?;
+	    if !subscribed && session.events.is_some() {
+		// Wake up periodically to forward events
+		websocket.get_ref().set_read_timeout(
+		    Some(Duration::from_secs_f64(Self::POLL_INTERVAL)))?;
+	    }
 	}
 	Ok(())
     }
 
     pub fn run(&mut self)->Result<()> {
+	let ctl = Arc::clone(&self.ctl);
+	spawn(move || {
+	    loop {
+		let interval = ctl.lock().unwrap().config.watch.interval;
+		sleep(Duration::from_secs_f64(interval));
+		if let Err(e) = ctl.lock().unwrap().watch() {
+		    error!(error = %e,"Watchdog failed");
+		}
+	    }
+	});
+
 	for stream in self.server.incoming() {
 	    let stream = stream?;
+	    let peer = stream.peer_addr()
+		.map(|a| a.to_string())
+		.unwrap_or_else(|_| "?".to_string());
 	    let ctl = Arc::clone(&self.ctl);
-	    spawn (|| {
-		match Self::handle(ctl,stream) {
-		    Ok(()) => (),
-		    Err(e) => eprintln!("Error: {}",e)
+	    spawn (move || {
+		if let Err(e) = Self::handle(ctl,&peer,stream) {
+		    error!(peer,error = %e,"Connection failed");
 		}
 	    });
 	}
@@ -287,37 +1349,346 @@ impl ApiServer {
     }
 }
 
+/// Settings given on the command line, which take precedence over
+/// the configuration file, including when it is reloaded
+struct Overrides {
+    config_path:Option<String>,
+    listen:Option<String>,
+    state_path:Option<String>,
+    webhook:Option<String>
+}
+
+impl Overrides {
+    fn load(&self)->Result<Config> {
+	let mut config =
+	    if let Some(path) = &self.config_path {
+		Config::load(path)?
+	    } else {
+		Config::default()
+	    };
+	if let Some(listen) = &self.listen {
+	    config.listen = listen.clone();
+	}
+	if let Some(state_path) = &self.state_path {
+	    config.state_path = state_path.clone();
+	}
+	if let Some(url) = &self.webhook {
+	    config.notify.targets.push(Target {
+		events:Vec::new(),
+		sink:Sink::Webhook { url:url.clone() }
+	    });
+	}
+	config.validate()?;
+	Ok(config)
+    }
+}
+
 fn main()->Result<()> {
-    let progname : String = std::env::args().nth(0).unwrap();
+    let progname : String = std::env::args().next().unwrap();
     
     let mut args = Arguments::from_env();
 
     if args.contains("-h") || args.contains("--help") {
-	eprintln!("Usage: {} [--listen ADDR:PORT]",
-		  progname);
+	eprintln!("Usage: {} [--config PATH] [--listen ADDR:PORT] \
+		   [--state-path PATH] [--webhook URL] {}",
+		  progname,LOG_USAGE);
 	return Ok(())
     }
 
-    let listen_addr = args.opt_value_from_str("--listen")?
-	.unwrap_or_else(|| "127.0.0.1:9001".to_string());
-
-    let state_path : String = args.opt_value_from_str("--state-path")?
-	.unwrap_or_else(|| "state.dat".to_string());
+    let overrides = Overrides {
+	config_path:args.opt_value_from_str("--config")?,
+	listen:args.opt_value_from_str("--listen")?,
+	state_path:args.opt_value_from_str("--state-path")?,
+	webhook:args.opt_value_from_str("--webhook")?
+    };
 
     let create_state = args.contains("--create-state");
 
+    let log_level : LogLevel = args.opt_value_from_str("--log-level")?
+	.unwrap_or(LogLevel::INFO);
+    let log_format : LogFormat = args.opt_value_from_str("--log-format")?
+	.unwrap_or(LogFormat::Text);
+
     let rest = args.finish();
     if !rest.is_empty() {
 	bail!("Invalid arguments: {:?}",rest);
     }
 
-    let config = Config { state_path };
+    discipline_net::logging::init(log_level,log_format)?;
+
+    let config = overrides.load()?;
 
     if create_state {
 	Controller::create_state(&config)?;
     }
-    
-    let mut api_srv = ApiServer::new(&listen_addr,config)?;
+
+    let listen = config.listen.clone();
+    let mut api_srv = ApiServer::new(config)?;
+    info!(listen,"Listening");
+
+    let ctl = Arc::clone(&api_srv.ctl);
+    let mut signals = Signals::new([SIGHUP])?;
+    spawn(move || {
+	for _ in signals.forever() {
+	    if overrides.config_path.is_none() {
+		warn!("No configuration file to reload");
+		continue;
+	    }
+	    match overrides.load() {
+		Ok(config) => {
+		    ctl.lock().unwrap().reconfigure(config);
+		    info!("Configuration reloaded");
+		},
+		Err(e) => error!(error = %e,"Configuration not reloaded")
+	    }
+	}
+    });
 
     api_srv.run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{
+	TimeZone,
+	Utc
+    };
+
+    /// Wed 2026-06-10 at the given time in Paris (CEST)
+    fn at(hour:u32,minute:u32)->f64 {
+	Utc.with_ymd_and_hms(2026,6,10,hour - 2,minute,0).unwrap().timestamp() as f64
+    }
+
+    fn clock()->Clock {
+	Clock::new("Europe/Paris").unwrap()
+    }
+
+    fn admin(name:&str)->Entity {
+	Entity::Administrator(name.to_string())
+    }
+
+    fn subject(name:&str)->Entity {
+	Entity::Subject(name.to_string())
+    }
+
+    fn get_status(subject:&str)->Command {
+	Command::GetStatus { subject:subject.to_string() }
+    }
+
+    fn authorize(subject:&str,t:f64)->Command {
+	Command::Authorize { subject:subject.to_string(),duration:Some(t) }
+    }
+
+    /// Two subjects, a full administrator, a babysitter limited to
+    /// alice during the day until 21:00, and an agent for alice
+    fn state()->ControllerState {
+	let mut state = ControllerState::new();
+	state.administrators.push("mom".to_string());
+	state.scoped_administrators.insert("granny".to_string(),Scope {
+	    subjects:Some(vec!["alice".to_string()]),
+	    max_grant:Some(3600.0),
+	    hours:Some("08:00-20:00".parse().unwrap()),
+	    expires:Some(at(21,0))
+	});
+	for name in ["alice","bob"] {
+	    state.subjects.insert(name.to_string(),ron::from_str("()").unwrap());
+	}
+	state.set_agents(BTreeMap::from([("pc".to_string(),
+					   vec!["alice".to_string()])]));
+	state
+    }
+
+    /// Error code of a refusal
+    fn refusal(res:std::result::Result<(),Response>)->Option<(ErrorCode,String)> {
+	match res {
+	    Ok(()) => None,
+	    Err(Response::Error { code,message }) => Some((code,message)),
+	    Err(resp) => panic!("Unexpected response {:?}",resp)
+	}
+    }
+
+    fn code(resp:Response)->Option<ErrorCode> {
+	match resp {
+	    Response::Ack => None,
+	    Response::Error { code,.. } => Some(code),
+	    resp => panic!("Unexpected response {:?}",resp)
+	}
+    }
+
+    #[test]
+    fn subjects_only_see_themselves() {
+	let (state,clock,t) = (state(),clock(),at(12,0));
+	let permit = |sender:&Entity,cmd:&Command|
+	    refusal(state.permit(&clock,sender,cmd,t)).map(|(code,_)| code);
+	let alice = subject("alice");
+	assert_eq!(permit(&alice,&get_status("alice")),None);
+	assert_eq!(permit(&alice,&get_status("bob")),
+		   Some(ErrorCode::PermissionDenied));
+	assert_eq!(permit(&alice,&authorize("alice",60.0)),
+		   Some(ErrorCode::PermissionDenied));
+	let request = |name:&str| Command::RequestTime {
+	    subject:name.to_string(),duration:60.0,reason:String::new()
+	};
+	assert_eq!(permit(&alice,&request("alice")),None);
+	assert_eq!(permit(&alice,&request("bob")),
+		   Some(ErrorCode::PermissionDenied));
+	assert!(state.may_see(&clock,&alice,"alice",t));
+	assert!(!state.may_see(&clock,&alice,"bob",t));
+    }
+
+    #[test]
+    fn scoped_administrators() {
+	let (state,clock,t) = (state(),clock(),at(12,0));
+	let permit = |sender:&Entity,cmd:&Command|
+	    refusal(state.permit(&clock,sender,cmd,t)).map(|(code,_)| code);
+	let granny = admin("granny");
+	assert_eq!(permit(&granny,&authorize("alice",60.0)),None);
+	assert_eq!(permit(&granny,&authorize("bob",60.0)),
+		   Some(ErrorCode::PermissionDenied));
+	assert_eq!(permit(&granny,&Command::GetAllStatus),None);
+	assert!(state.may_see(&clock,&granny,"alice",t));
+	assert!(!state.may_see(&clock,&granny,"bob",t));
+	let remove = Command::RemoveGuest { name:"granny".to_string() };
+	assert_eq!(permit(&granny,&remove),Some(ErrorCode::PermissionDenied));
+	assert_eq!(permit(&admin("mom"),&remove),None);
+	assert_eq!(permit(&admin("mom"),&authorize("bob",60.0)),None);
+	assert_eq!(permit(&admin("dad"),&get_status("alice")),
+		   Some(ErrorCode::UnknownAdministrator));
+    }
+
+    #[test]
+    fn scoped_administrators_outside_their_hours_or_expired() {
+	let (state,clock) = (state(),clock());
+	let granny = admin("granny");
+	let permit = |t:f64|
+	    refusal(state.permit(&clock,&granny,&get_status("alice"),t))
+	    .map(|(code,_)| code);
+	assert_eq!(permit(at(8,0)),None);
+	assert_eq!(permit(at(7,59)),Some(ErrorCode::PermissionDenied));
+	assert_eq!(permit(at(20,30)),Some(ErrorCode::PermissionDenied));
+	// Within the hours on the next day, but expired
+	assert_eq!(permit(at(12,0) + 86400.0),Some(ErrorCode::PermissionDenied));
+	assert!(!state.may_see(&clock,&granny,"alice",at(7,0)));
+    }
+
+    #[test]
+    fn agents_see_the_subjects_of_their_machine() {
+	let (state,clock,t) = (state(),clock(),at(12,0));
+	let pc = Entity::Agent("pc".to_string());
+	let statuses = |names:&[&str]| Command::GetStatuses {
+	    subjects:names.iter().map(|s| s.to_string()).collect()
+	};
+	assert!(refusal(state.permit(&clock,&pc,&statuses(&["alice"]),t)).is_none());
+	assert!(refusal(state.permit(&clock,&pc,&statuses(&["alice","bob"]),t))
+		.is_some());
+	let other = Entity::Agent("laptop".to_string());
+	assert!(refusal(state.permit(&clock,&other,&statuses(&[]),t)).is_some());
+    }
+
+    #[test]
+    fn unknown_subjects_are_refused_like_hidden_ones() {
+	let (state,clock,t) = (state(),clock(),at(12,0));
+	for sender in [subject("alice"),admin("granny")] {
+	    let refused = |name:&str| {
+		let (code,message) =
+		    refusal(state.permit(&clock,&sender,&get_status(name),t))
+		    .expect("Not refused");
+		(code,message.replace(name,"NAME"))
+	    };
+	    assert_eq!(refused("bob"),refused("carol"));
+	}
+    }
+
+    #[test]
+    fn login_checks_are_not_pings() {
+	let (mut state,clock,calendar) = (state(),clock(),Calendar::default());
+	let mut send = |payload:Command| {
+	    let env = Envelope { sender:subject("alice"),payload,
+				 signature:String::new() };
+	    state.handle(&clock,&calendar,&env).unwrap();
+	    state.subjects["alice"].last_ping
+	};
+	let subject = "alice".to_string();
+	assert!(send(Command::CheckLogin { subject:subject.clone() }).is_none());
+	assert!(send(Command::GetStatus { subject }).is_some());
+    }
+
+    #[test]
+    fn guest_expiry_is_bounded() {
+	let (state,t) = (state(),at(12,0));
+	let check = |expires:Option<f64>| state.check_guest("sitter",&Scope {
+	    subjects:None,
+	    max_grant:None,
+	    hours:None,
+	    expires
+	},t);
+	assert!(check(Some(t + 3600.0)).is_ok());
+	assert!(check(Some(t + MAX_DURATION)).is_ok());
+	for expires in [None,Some(t - 1.0),Some(f64::NAN),Some(1e300),
+			Some(t + MAX_DURATION + 1.0)] {
+	    assert!(check(expires).is_err(),"Accepted {:?}",expires);
+	}
+    }
+
+    #[test]
+    fn authorizations_within_limits() {
+	let (mut state,clock) = (state(),clock());
+	let policy = &mut state.subjects.get_mut("alice").unwrap().policy;
+	policy.max_grant = Some(2.0*3600.0);
+	policy.max_per_day = Some(3.0*3600.0);
+	let t = at(10,0);
+	let mom = admin("mom");
+	let mut authorize = |sender:&Entity,d:f64,t:f64|
+	    code(state.authorize(&clock,sender,"alice",Some(d),t).unwrap());
+
+	for d in [f64::NAN,f64::INFINITY,-1.0,MAX_DURATION + 1.0] {
+	    assert_eq!(authorize(&mom,d,t),Some(ErrorCode::InvalidArgument));
+	}
+	assert_eq!(authorize(&mom,3.0*3600.0,t),Some(ErrorCode::LimitExceeded));
+	// The guest has a lower limit of its own
+	assert_eq!(authorize(&admin("granny"),2.0*3600.0,t),
+		   Some(ErrorCode::LimitExceeded));
+	assert_eq!(authorize(&mom,2.0*3600.0,t),None);
+	// Replacing the time left only counts what it adds
+	assert_eq!(authorize(&mom,2.0*3600.0,t + 1800.0),None);
+	// 2h30 granted today, so 1h more would exceed the daily limit
+	let t = t + 4.0*3600.0;
+	assert_eq!(authorize(&mom,3600.0,t),Some(ErrorCode::LimitExceeded));
+	assert_eq!(authorize(&mom,1800.0,t),None);
+	// The next day starts afresh
+	assert_eq!(authorize(&mom,2.0*3600.0,t + 86400.0),None);
+    }
+
+    #[test]
+    fn extensions_are_limited_by_the_time_added() {
+	let (mut state,clock,calendar) = (state(),clock(),Calendar::default());
+	state.subjects.get_mut("alice").unwrap().policy.max_grant =
+	    Some(2.0*3600.0);
+	let t = at(10,0);
+	let extend = |state:&mut ControllerState,sender:&Entity,duration:f64| {
+	    let env = Envelope {
+		sender:sender.clone(),
+		payload:Command::Extend { subject:"alice".to_string(),duration },
+		signature:String::new()
+	    };
+	    code(state.execute(&clock,&calendar,&env,t).unwrap())
+	};
+	let (granny,mom) = (admin("granny"),admin("mom"));
+	let s = &mut state;
+	assert_eq!(extend(s,&granny,50.0*60.0),None);
+	// 65 min in total, but only 15 min added
+	assert_eq!(extend(s,&granny,15.0*60.0),None);
+	assert_eq!(extend(s,&granny,2.0*3600.0),Some(ErrorCode::LimitExceeded));
+	assert_eq!(extend(s,&mom,3.0*3600.0),Some(ErrorCode::LimitExceeded));
+	assert_eq!(extend(s,&mom,2.0*3600.0),None);
+	assert_eq!(extend(s,&mom,f64::NAN),Some(ErrorCode::InvalidArgument));
+	assert_eq!(extend(s,&admin("alice"),60.0),
+		   Some(ErrorCode::UnknownAdministrator));
+	assert_eq!(s.subjects["alice"].time_remaining(t),(65.0 + 120.0)*60.0);
+	// The total is bounded like any authorization
+	s.subjects.get_mut("alice").unwrap().authorized_until =
+	    Some(t + MAX_DURATION - 60.0);
+	assert_eq!(extend(s,&mom,3600.0),Some(ErrorCode::LimitExceeded));
+    }
+}
@@ -0,0 +1,505 @@
+use serde::{
+    Deserialize,
+    Serialize
+};
+use std::{
+    fs::File,
+    io::{
+	BufReader,
+	BufWriter
+    },
+    path::Path,
+    sync::{
+	Arc,
+	Mutex,
+	mpsc::{
+	    channel,
+	    RecvTimeoutError,
+	    Sender
+	}
+    },
+    thread::{
+	spawn,
+	JoinHandle
+    },
+    time::Duration
+};
+use anyhow::{
+    anyhow,
+    Result
+};
+use lettre::{
+    Message,
+    SmtpTransport,
+    Transport,
+    transport::smtp::authentication::Credentials
+};
+use tracing::{
+    error,
+    warn
+};
+use discipline_net::*;
+use crate::{
+    now,
+    Updateable
+};
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub server:String,
+    #[serde(default)]
+    pub port:Option<u16>,
+    /// Use STARTTLS instead of implicit TLS
+    #[serde(default)]
+    pub starttls:bool,
+    #[serde(default)]
+    pub username:Option<String>,
+    #[serde(default)]
+    pub password:Option<String>,
+    pub from:String,
+    pub to:Vec<String>
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum Sink {
+    Webhook { url:String },
+    Email(EmailConfig)
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// Events to send to this target, all of them if empty
+    #[serde(default)]
+    pub events:Vec<EventKind>,
+    pub sink:Sink
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub queue_path:String,
+    pub targets:Vec<Target>
+}
+
+impl Default for NotifyConfig {
+    fn default()->Self {
+	Self {
+	    queue_path:"notify.dat".to_string(),
+	    targets:Vec::new()
+	}
+    }
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+struct Pending {
+    sink:Sink,
+    event:Event,
+    attempts:u32,
+    next_attempt:f64
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+struct Queue {
+    serial:u64,
+    pending:Vec<Pending>
+}
+
+impl Updateable for Queue {
+    fn new()->Self {
+	Self {
+	    serial:0,
+	    pending:Vec::new()
+	}
+    }
+
+    fn load<P:AsRef<Path>>(path:P)->Result<Self> {
+	let fd = File::open(path)?;
+	let buf = BufReader::new(fd);
+	Ok(ron::de::from_reader(buf)?)
+    }
+
+    fn save<P:AsRef<Path>>(&self,path:P)->Result<()> {
+	let fd = File::create(path)?;
+	let buf = BufWriter::new(fd);
+	Ok(ron::ser::to_writer(buf,&self)?)
+    }
+
+    fn updated(&mut self) {
+	self.serial += 1;
+    }
+
+    fn serial(&self)->u64 {
+	self.serial
+    }
+}
+
+impl Pending {
+    const RETRY_DELAY : f64 = 30.0;
+    const MAX_RETRY_DELAY : f64 = 3600.0;
+    const MAX_ATTEMPTS : u32 = 20;
+
+    fn deliver(&self)->Result<()> {
+	match &self.sink {
+	    Sink::Webhook { url } => {
+		ureq::post(url).send_json(&self.event)?;
+	    },
+	    Sink::Email(cfg) => {
+		let mut builder = Message::builder()
+		    .from(cfg.from.parse()?)
+		    .subject(format!("Discipline: {}",self.event));
+		for to in cfg.to.iter() {
+		    builder = builder.to(to.parse()?);
+		}
+		let body = format!("{}\n\n{}\n",
+				   self.event,
+				   serde_json::to_string_pretty(&self.event)?);
+		let msg = builder.body(body)?;
+		let mut transport =
+		    if cfg.starttls {
+			SmtpTransport::starttls_relay(&cfg.server)?
+		    } else {
+			SmtpTransport::relay(&cfg.server)?
+		    };
+		if let Some(port) = cfg.port {
+		    transport = transport.port(port);
+		}
+		if let (Some(user),Some(pass)) = (&cfg.username,&cfg.password) {
+		    transport = transport.credentials(
+			Credentials::new(user.clone(),pass.clone()));
+		}
+		transport.build().send(&msg)?;
+	    }
+	}
+	Ok(())
+    }
+
+    /// Schedule the next attempt after a failure, with exponential
+    /// backoff; returns false when the notification should be dropped
+    fn failed(&mut self,t_now:f64)->bool {
+	self.attempts += 1;
+	let delay = (Self::RETRY_DELAY * 2.0_f64.powi(self.attempts as i32 - 1))
+	    .min(Self::MAX_RETRY_DELAY);
+	self.next_attempt = t_now + delay;
+	self.attempts < Self::MAX_ATTEMPTS
+    }
+}
+
+pub struct Notifier {
+    config:NotifyConfig,
+    queue:Arc<Mutex<Queue>>,
+    subscribers:Vec<Sender<Event>>,
+    /// Dropped to stop the delivery thread
+    stop:Option<Sender<()>>,
+    worker:Option<JoinHandle<()>>
+}
+
+impl Notifier {
+    const POLL_INTERVAL : f64 = 1.0;
+
+    pub fn new(config:NotifyConfig)->Result<Self> {
+	let queue =
+	    if Path::new(&config.queue_path).exists() {
+		Queue::load(&config.queue_path)
+		    .map_err(|e| anyhow!("Cannot load notification queue {:?}: {}",
+					 config.queue_path,e))?
+	    } else {
+		Queue::new()
+	    };
+	let queue = Arc::new(Mutex::new(queue));
+	let mut this = Self {
+	    config,
+	    queue,
+	    subscribers:Vec::new(),
+	    stop:None,
+	    worker:None
+	};
+	this.start();
+	Ok(this)
+    }
+
+    fn start(&mut self) {
+	let queue = Arc::clone(&self.queue);
+	let queue_path = self.config.queue_path.clone();
+	let (stop,stopped) = channel::<()>();
+	self.stop = Some(stop);
+	self.worker = Some(spawn(move || {
+	    let interval = Duration::from_secs_f64(Self::POLL_INTERVAL);
+	    while let Err(RecvTimeoutError::Timeout) =
+		stopped.recv_timeout(interval) {
+		    Self::deliver_due(&queue,&queue_path,now());
+		}
+	}));
+    }
+
+    /// Deliver the notifications due at t_now, without holding the
+    /// lock of the queue while talking to the sinks
+    fn deliver_due(queue:&Mutex<Queue>,queue_path:&str,t_now:f64) {
+	let due : Vec<Pending> = {
+	    let mut queue = queue.lock().unwrap();
+	    let (due,later) = queue.pending.drain(..)
+		.partition(|p| p.next_attempt <= t_now);
+	    queue.pending = later;
+	    due
+	};
+	if due.is_empty() {
+	    return;
+	}
+	let mut retry = Vec::new();
+	for mut p in due {
+	    match p.deliver() {
+		Ok(()) => (),
+		Err(e) => {
+		    warn!(subject = p.event.subject(),
+			  event = ?p.event.kind(),
+			  attempts = p.attempts + 1,
+			  error = %e,
+			  "Notification failed");
+		    if p.failed(now()) {
+			retry.push(p);
+		    } else {
+			error!(subject = p.event.subject(),
+			       event = ?p.event.kind(),
+			       "Dropping notification");
+		    }
+		}
+	    }
+	}
+	let mut queue = queue.lock().unwrap();
+	queue.pending.append(&mut retry);
+	queue.updated();
+	if let Err(e) = queue.atomic_replace(queue_path) {
+	    error!(error = %e,"Cannot save notification queue");
+	}
+    }
+
+    pub fn reconfigure(&mut self,config:NotifyConfig) {
+	if config.queue_path != self.config.queue_path {
+	    warn!(queue_path = self.config.queue_path,
+		  "Changing the notification queue path requires a restart");
+	}
+	self.config.targets = config.targets;
+    }
+
+    pub fn subscribe(&mut self,sender:Sender<Event>) {
+	self.subscribers.push(sender);
+    }
+
+    pub fn notify(&mut self,event:Event) {
+	// Subscribers whose connection went away are dropped here
+	self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+
+	let kind = event.kind();
+	let t_now = now();
+	let mut queue = self.queue.lock().unwrap();
+	let mut queued = false;
+	for target in self.config.targets.iter() {
+	    if target.events.is_empty() || target.events.contains(&kind) {
+		queue.pending.push(Pending {
+		    sink:target.sink.clone(),
+		    event:event.clone(),
+		    attempts:0,
+		    next_attempt:t_now
+		});
+		queued = true;
+	    }
+	}
+	if queued {
+	    queue.updated();
+	    if let Err(e) = queue.atomic_replace(&self.config.queue_path) {
+		error!(error = %e,"Cannot save notification queue");
+	    }
+	}
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+	self.stop.take();
+	if let Some(worker) = self.worker.take() {
+	    if worker.join().is_err() {
+		error!("Notification thread panicked");
+	    }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+	io::{
+	    BufRead,
+	    Read,
+	    Write
+	},
+	net::TcpListener,
+	path::PathBuf,
+	sync::mpsc::{
+	    channel,
+	    Receiver
+	}
+    };
+
+    /// Stand-in HTTP server answering one request per status, and
+    /// passing on the bodies it receives
+    fn serve(statuses:Vec<u16>)->(String,Receiver<String>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}/hook",listener.local_addr().unwrap());
+	let (sender,receiver) = channel();
+	spawn(move || {
+	    for status in statuses {
+		let (stream,_) = listener.accept().unwrap();
+		let mut reader = BufReader::new(&stream);
+		let mut length = 0;
+		loop {
+		    let mut line = String::new();
+		    reader.read_line(&mut line).unwrap();
+		    let line = line.trim_end();
+		    if line.is_empty() {
+			break;
+		    }
+		    if let Some((name,value)) = line.split_once(':') {
+			if name.eq_ignore_ascii_case("content-length") {
+			    length = value.trim().parse().unwrap();
+			}
+		    }
+		}
+		let mut body = vec![0;length];
+		reader.read_exact(&mut body).unwrap();
+		write!(&stream,"HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\
+				Connection: close\r\n\r\n",status).unwrap();
+		let _ = sender.send(String::from_utf8(body).unwrap());
+	    }
+	});
+	(url,receiver)
+    }
+
+    fn queue_path(name:&str)->PathBuf {
+	let path = std::env::temp_dir()
+	    .join(format!("discipline-notify-{}-{}.dat",std::process::id(),name));
+	let _ = std::fs::remove_file(&path);
+	path
+    }
+
+    fn event()->Event {
+	Event::AgentSilent { subject:"alice".to_string(),silent_for:600.0 }
+    }
+
+    fn pending(url:&str,t:f64)->Pending {
+	Pending {
+	    sink:Sink::Webhook { url:url.to_string() },
+	    event:event(),
+	    attempts:0,
+	    next_attempt:t
+	}
+    }
+
+    #[test]
+    fn webhook_delivery() {
+	let (url,bodies) = serve(vec![200]);
+	let path = queue_path("delivery");
+	let queue = Mutex::new(Queue { serial:0,pending:vec![pending(&url,0.0)] });
+	Notifier::deliver_due(&queue,path.to_str().unwrap(),now());
+	let body : serde_json::Value = serde_json::from_str(&bodies.recv().unwrap())
+	    .unwrap();
+	assert_eq!(body,serde_json::to_value(event()).unwrap());
+	assert!(queue.lock().unwrap().pending.is_empty());
+	std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+	let (url,bodies) = serve(vec![500,503,200]);
+	let path = queue_path("retry");
+	let path = path.to_str().unwrap();
+	let t0 = now();
+	let queue = Mutex::new(Queue { serial:0,pending:vec![pending(&url,t0)] });
+
+	Notifier::deliver_due(&queue,path,t0);
+	let t1 = {
+	    let queue = queue.lock().unwrap();
+	    assert_eq!(queue.pending.len(),1);
+	    assert_eq!(queue.pending[0].attempts,1);
+	    let delay = queue.pending[0].next_attempt - t0;
+	    assert!((Pending::RETRY_DELAY..Pending::RETRY_DELAY + 5.0).contains(&delay));
+	    queue.pending[0].next_attempt
+	};
+
+	// Not due yet
+	Notifier::deliver_due(&queue,path,t1 - 1.0);
+	assert_eq!(queue.lock().unwrap().pending[0].attempts,1);
+
+	// The delay doubles, counting from the failed attempt
+	let before = now();
+	Notifier::deliver_due(&queue,path,t1);
+	let t2 = {
+	    let queue = queue.lock().unwrap();
+	    assert_eq!(queue.pending[0].attempts,2);
+	    let delay = queue.pending[0].next_attempt - before;
+	    assert!((2.0*Pending::RETRY_DELAY..2.0*Pending::RETRY_DELAY + 5.0)
+		    .contains(&delay));
+	    queue.pending[0].next_attempt
+	};
+
+	Notifier::deliver_due(&queue,path,t2);
+	assert!(queue.lock().unwrap().pending.is_empty());
+	assert_eq!(bodies.iter().take(3).count(),3);
+	std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn backoff_is_capped_and_gives_up() {
+	let mut p = pending("http://127.0.0.1:1/",0.0);
+	for _ in 1..Pending::MAX_ATTEMPTS {
+	    assert!(p.failed(0.0));
+	    assert!(p.next_attempt <= Pending::MAX_RETRY_DELAY);
+	}
+	assert_eq!(p.next_attempt,Pending::MAX_RETRY_DELAY);
+	assert!(!p.failed(0.0));
+    }
+
+    #[test]
+    fn queue_survives_restart() {
+	// Nothing listens on this address, so delivery keeps failing
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}/hook",listener.local_addr().unwrap());
+	drop(listener);
+	let path = queue_path("restart");
+	let config = NotifyConfig {
+	    queue_path:path.to_str().unwrap().to_string(),
+	    targets:vec![Target { events:Vec::new(),
+				  sink:Sink::Webhook { url:url.clone() } }]
+	};
+	let mut notifier = Notifier::new(config.clone()).unwrap();
+	notifier.notify(event());
+	drop(notifier);
+
+	let notifier = Notifier::new(config).unwrap();
+	let queue = notifier.queue.lock().unwrap();
+	assert_eq!(queue.pending.len(),1);
+	match &queue.pending[0].sink {
+	    Sink::Webhook { url:u } => assert_eq!(u,&url),
+	    _ => panic!("Wrong sink")
+	}
+	assert_eq!(queue.pending[0].event.subject(),"alice");
+	drop(queue);
+	std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delivery_thread_stops_on_drop() {
+	let path = queue_path("stop");
+	let config = NotifyConfig {
+	    queue_path:path.to_str().unwrap().to_string(),
+	    targets:Vec::new()
+	};
+	let notifier = Notifier::new(config).unwrap();
+	let queue = Arc::clone(&notifier.queue);
+	assert_eq!(Arc::strong_count(&queue),3);
+	drop(notifier);
+	// The thread has released its reference to the queue
+	assert_eq!(Arc::strong_count(&queue),1);
+    }
+}
@@ -0,0 +1,393 @@
+mod term;
+
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    net::TcpStream,
+    time::{
+	Duration,
+	Instant
+    }
+};
+use url::Url;
+use tungstenite::{
+    connect,
+    stream::MaybeTlsStream,
+    Message,
+    WebSocket
+};
+use pico_args::Arguments;
+use anyhow::{
+    anyhow,
+    bail,
+    Result
+};
+use discipline_net::*;
+use term::{
+    Key,
+    Terminal
+};
+
+struct Connection {
+    socket:WebSocket<MaybeTlsStream<TcpStream>>,
+    sender:Entity,
+    encoding:Encoding,
+    features:Vec<Feature>
+}
+
+impl Connection {
+    const POLL_INTERVAL : f64 = 0.1;
+
+    fn open(url:&Url,sender:Entity)->Result<Self> {
+	let (socket,_response) = connect(url.clone())?;
+	let mut conn = Self { socket,sender,encoding:Encoding::Json,
+			      features:Vec::new() };
+	conn.send(Command::Hello { version:PROTOCOL_VERSION,
+				   features:Feature::ALL.to_vec() })?;
+	conn.features =
+	    match conn.receive()?.into_result()? {
+		Response::Welcome { features,.. } => features,
+		_ => bail!("Unexpected response")
+	    };
+	if !conn.features.contains(&Feature::AllStatus) {
+	    bail!("The server does not support GetAllStatus");
+	}
+	if conn.features.contains(&Feature::Cbor) {
+	    conn.encoding = Encoding::Cbor;
+	}
+	if let MaybeTlsStream::Plain(stream) = conn.socket.get_ref() {
+	    stream.set_read_timeout(
+		Some(Duration::from_secs_f64(Self::POLL_INTERVAL)))?;
+	}
+	Ok(conn)
+    }
+
+    fn send(&mut self,payload:Command)->Result<()> {
+	let cmd = Envelope {
+	    sender:self.sender.clone(),
+	    signature:"\\_'')_/".to_string(),
+	    payload
+	};
+	let msg =
+	    match self.encoding {
+		Encoding::Json => Message::Text(serde_json::to_string(&cmd)?),
+		Encoding::Cbor => Message::Binary(
+		    Encoding::Cbor.encode(&cmd).map_err(|e| anyhow!(e))?)
+	    };
+	self.socket.send(msg)?;
+	Ok(())
+    }
+
+    fn receive(&mut self)->Result<Response> {
+	loop {
+	    if let Some(resp) = self.poll()? {
+		return Ok(resp);
+	    }
+	}
+    }
+
+    /// Next response or event, if one arrives within the poll interval
+    fn poll(&mut self)->Result<Option<Response>> {
+	let msg =
+	    match self.socket.read() {
+		Ok(msg) => msg,
+		Err(tungstenite::Error::Io(e))
+		    if e.kind() == ErrorKind::WouldBlock ||
+		    e.kind() == ErrorKind::TimedOut => return Ok(None),
+		Err(e) => return Err(e.into())
+	    };
+	let env =
+	    match msg {
+		Message::Text(u) => decode_response(&u)?,
+		Message::Binary(u) => Encoding::Cbor.decode_response(&u)?,
+		Message::Close(_) => bail!("Disconnected by the server"),
+		_ => return Ok(None)
+	    };
+	Ok(Some(env.payload))
+    }
+}
+
+struct Request {
+    subject:String,
+    duration:f64,
+    reason:String
+}
+
+#[derive(Clone,Copy)]
+enum Action {
+    Authorize,
+    Extend
+}
+
+struct App {
+    title:String,
+    statuses:Vec<Status>,
+    selected:usize,
+    requests:Vec<Request>,
+    events:VecDeque<String>,
+    message:String,
+    /// Action waiting for a number of minutes, and what was typed
+    prompt:Option<(Action,String)>,
+    refresh_now:bool
+}
+
+impl App {
+    const MAX_EVENTS : usize = 8;
+
+    fn subject(&self)->Option<&Status> {
+	self.statuses.get(self.selected)
+    }
+
+    fn response(&mut self,resp:Response) {
+	match resp {
+	    Response::Statuses(statuses) => {
+		self.statuses = statuses;
+		self.selected = self.selected.min(self.statuses.len().saturating_sub(1));
+	    },
+	    Response::Ack => {
+		self.message = "Done".to_string();
+		self.refresh_now = true;
+	    },
+	    Response::Error { code,message } =>
+		self.message = format!("Error {:?}: {}",code,message),
+	    Response::Event(event) => {
+		match &event {
+		    Event::TimeRequested { subject,duration,reason } =>
+			self.requests.push(Request { subject:subject.clone(),
+						     duration:*duration,
+						     reason:reason.clone() }),
+		    Event::AuthorizationGranted { subject,.. } =>
+			self.requests.retain(|r| &r.subject != subject),
+		    _ => ()
+		}
+		self.events.push_front(event.to_string());
+		self.events.truncate(Self::MAX_EVENTS);
+		self.refresh_now = true;
+	    },
+	    _ => ()
+	}
+    }
+
+    fn authorize(&mut self,conn:&mut Connection,subject:String,
+		 duration:Option<f64>)->Result<()> {
+	if let Some(Err(e)) = duration.map(check_duration) {
+	    self.message = e;
+	    return Ok(());
+	}
+	self.message = match duration {
+	    Some(t) => format!("Authorizing {} for {}",subject,Approx(t)),
+	    None => format!("Cancelling the time of {}",subject)
+	};
+	conn.send(Command::Authorize { subject,duration })
+    }
+
+    /// Add to the authorization of a subject, which the time
+    /// remaining in the status may not show when a curfew cuts it
+    /// short
+    fn extend(&mut self,conn:&mut Connection,subject:String,
+	      duration:f64)->Result<()> {
+	if !conn.features.contains(&Feature::Extend) {
+	    self.message = "The server cannot extend authorizations".to_string();
+	    return Ok(());
+	}
+	if let Err(e) = check_duration(duration) {
+	    self.message = e;
+	    return Ok(());
+	}
+	self.message = format!("Extending the time of {} by {}",
+			       subject,Approx(duration));
+	conn.send(Command::Extend { subject,duration })
+    }
+
+    /// Index of the request to approve or dismiss: the first one of
+    /// the selected subject, or else the first one
+    fn request(&self)->Option<usize> {
+	let subject = self.subject().map(|s| s.subject.as_str());
+	self.requests.iter().position(|r| Some(r.subject.as_str()) == subject)
+	    .or_else(|| (!self.requests.is_empty()).then_some(0))
+    }
+
+    /// Handle a key, false to quit
+    fn key(&mut self,conn:&mut Connection,key:Key)->Result<bool> {
+	if let Some((action,mut input)) = self.prompt.take() {
+	    match key {
+		Key::Char(c) if c.is_ascii_digit() => input.push(c),
+		Key::Backspace => { input.pop(); },
+		Key::Enter => {
+		    let Some(status) = self.subject().cloned() else {
+			return Ok(true)
+		    };
+		    let Ok(minutes) = input.parse::<f64>() else {
+			self.message = "Invalid number of minutes".to_string();
+			return Ok(true)
+		    };
+		    let t = 60.0*minutes;
+		    match action {
+			Action::Authorize =>
+			    self.authorize(conn,status.subject,Some(t))?,
+			Action::Extend => self.extend(conn,status.subject,t)?
+		    }
+		    return Ok(true)
+		},
+		Key::Escape => {
+		    self.message.clear();
+		    return Ok(true)
+		},
+		_ => ()
+	    }
+	    self.prompt = Some((action,input));
+	    return Ok(true)
+	}
+	match key {
+	    Key::Char('q') => return Ok(false),
+	    Key::Up | Key::Char('k') =>
+		self.selected = self.selected.saturating_sub(1),
+	    Key::Down | Key::Char('j') =>
+		self.selected = (self.selected + 1)
+		.min(self.statuses.len().saturating_sub(1)),
+	    Key::Char('a') if self.subject().is_some() =>
+		self.prompt = Some((Action::Authorize,String::new())),
+	    Key::Char('e') if self.subject().is_some() =>
+		self.prompt = Some((Action::Extend,String::new())),
+	    Key::Char('c') => {
+		if let Some(status) = self.subject() {
+		    let subject = status.subject.clone();
+		    self.authorize(conn,subject,None)?;
+		}
+	    },
+	    Key::Char('y') => {
+		if let Some(i) = self.request() {
+		    let r = self.requests.remove(i);
+		    self.extend(conn,r.subject,r.duration)?;
+		}
+	    },
+	    Key::Char('n') => {
+		if let Some(i) = self.request() {
+		    let r = self.requests.remove(i);
+		    self.message = format!("Dismissed the request of {}",r.subject);
+		}
+	    },
+	    Key::Char('r') => self.refresh_now = true,
+	    _ => ()
+	}
+	Ok(true)
+    }
+
+    fn lines(&self)->Vec<String> {
+	let mut lines = vec![self.title.clone(),String::new()];
+	lines.push(format!("  {:<16} {:>10}  {:<22} {:>10} {:>8}",
+			   "Subject","Remaining","Limited by","Last seen","Bank"));
+	for (i,s) in self.statuses.iter().enumerate() {
+	    let limited_by =
+		if s.holiday {
+		    format!("{}, holiday",s.limited_by)
+		} else {
+		    s.limited_by.to_string()
+		};
+	    lines.push(format!("{} {:<16} {:>10}  {:<22} {:>10} {:>8}",
+			       if i == self.selected { ">" } else { " " },
+			       s.subject,
+			       Approx(s.time_remaining).to_string(),
+			       limited_by,
+			       s.last_ping.map(|t| Approx(t).to_string())
+			       .unwrap_or_else(|| "never".to_string()),
+			       Approx(s.bank).to_string()));
+	}
+	if !self.requests.is_empty() {
+	    lines.push(String::new());
+	    lines.push("Requests".to_string());
+	    for r in self.requests.iter() {
+		lines.push(format!("  {} asks for {}: {}",
+				   r.subject,Approx(r.duration),r.reason));
+	    }
+	}
+	if !self.events.is_empty() {
+	    lines.push(String::new());
+	    lines.push("Events".to_string());
+	    for e in self.events.iter() {
+		lines.push(format!("  {}",e));
+	    }
+	}
+	lines.push(String::new());
+	lines.push("[j/k] select  [a]uthorize  [e]xtend  [c]ancel  \
+		    [y/n] request  [r]efresh  [q]uit".to_string());
+	lines.push(
+	    match &self.prompt {
+		Some((Action::Authorize,input)) =>
+		    format!("Authorize for how many minutes? {}_",input),
+		Some((Action::Extend,input)) =>
+		    format!("Extend by how many minutes? {}_",input),
+		None => self.message.clone()
+	    });
+	lines
+    }
+}
+
+fn run(conn:&mut Connection,app:&mut App,refresh:f64)->Result<()> {
+    let term = Terminal::open()?;
+    let mut last_refresh : Option<Instant> = None;
+    loop {
+	for key in term.keys()? {
+	    if !app.key(conn,key)? {
+		return Ok(());
+	    }
+	}
+	let due = last_refresh
+	    .map(|t| t.elapsed().as_secs_f64() >= refresh)
+	    .unwrap_or(true);
+	if due || app.refresh_now {
+	    conn.send(Command::GetAllStatus)?;
+	    last_refresh = Some(Instant::now());
+	    app.refresh_now = false;
+	}
+	if let Some(resp) = conn.poll()? {
+	    app.response(resp);
+	}
+	term.draw(&app.lines())?;
+    }
+}
+
+fn main()->Result<()> {
+    let progname : String = std::env::args().next().unwrap();
+
+    let mut args = Arguments::from_env();
+
+    if args.contains("-h") || args.contains("--help") {
+	eprintln!("Usage: {} [--url ws://ADDR:PORT/] --sender-admin NAME \
+		   [--refresh SECONDS]",progname);
+	return Ok(())
+    }
+
+    let admin : String = args.value_from_str("--sender-admin")?;
+    let refresh : f64 = args.opt_value_from_str("--refresh")?.unwrap_or(5.0);
+    let url = args.opt_value_from_str("--url")?
+	.unwrap_or_else(|| "ws://127.0.0.1:9001".to_string());
+    let url = Url::parse(&url)?;
+
+    let rest = args.finish();
+    if !rest.is_empty() {
+	bail!("Invalid arguments: {:?}",rest);
+    }
+    if !(refresh > 0.0 && refresh <= MAX_DURATION) {
+	bail!("Invalid refresh interval {}",refresh);
+    }
+
+    let sender = Entity::Administrator(admin);
+    let title = format!("discipline: {} on {}",sender,url);
+    let mut conn = Connection::open(&url,sender)?;
+    if conn.features.contains(&Feature::Events) {
+	conn.send(Command::Subscribe)?;
+	conn.receive()?.into_result()?;
+    }
+    let mut app = App {
+	title,
+	statuses:Vec::new(),
+	selected:0,
+	requests:Vec::new(),
+	events:VecDeque::new(),
+	message:String::new(),
+	prompt:None,
+	refresh_now:false
+    };
+    run(&mut conn,&mut app,refresh)
+}
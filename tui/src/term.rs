@@ -0,0 +1,113 @@
+//! Just enough terminal handling for a full-screen table: raw mode,
+//! the alternate screen and key decoding
+
+use std::io::{
+    Read,
+    Write
+};
+use anyhow::{
+    anyhow,
+    Result
+};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Enter,
+    Backspace,
+    Escape
+}
+
+/// Puts the terminal back as it was when dropped
+pub struct Terminal {
+    saved:libc::termios
+}
+
+impl Terminal {
+    pub fn open()->Result<Self> {
+	let mut saved : libc::termios = unsafe { std::mem::zeroed() };
+	if unsafe { libc::tcgetattr(libc::STDIN_FILENO,&mut saved) } < 0 {
+	    return Err(anyhow!("Standard input is not a terminal: {}",
+			       std::io::Error::last_os_error()));
+	}
+	let mut raw = saved;
+	unsafe { libc::cfmakeraw(&mut raw) };
+	// Reads return at once, with whatever was typed
+	raw.c_cc[libc::VMIN] = 0;
+	raw.c_cc[libc::VTIME] = 0;
+	if unsafe { libc::tcsetattr(libc::STDIN_FILENO,libc::TCSANOW,&raw) } < 0 {
+	    return Err(anyhow!("Cannot set raw mode: {}",
+			       std::io::Error::last_os_error()));
+	}
+	print!("\x1b[?1049h\x1b[?25l");
+	std::io::stdout().flush()?;
+	Ok(Self { saved })
+    }
+
+    pub fn size(&self)->(usize,usize) {
+	let mut ws : libc::winsize = unsafe { std::mem::zeroed() };
+	if unsafe { libc::ioctl(libc::STDOUT_FILENO,libc::TIOCGWINSZ,&mut ws) } < 0
+	    || ws.ws_col == 0 {
+		(80,24)
+	    } else {
+		(ws.ws_col as usize,ws.ws_row as usize)
+	    }
+    }
+
+    pub fn keys(&self)->Result<Vec<Key>> {
+	let mut buf = [0;64];
+	let n = std::io::stdin().read(&mut buf)?;
+	let mut keys = Vec::new();
+	let mut input = buf[..n].iter().copied().peekable();
+	while let Some(b) = input.next() {
+	    let key =
+		match b {
+		    0x1b if input.peek() == Some(&b'[') => {
+			input.next();
+			match input.next() {
+			    Some(b'A') => Key::Up,
+			    Some(b'B') => Key::Down,
+			    _ => continue
+			}
+		    },
+		    0x1b => Key::Escape,
+		    b'\r' | b'\n' => Key::Enter,
+		    0x7f | 0x08 => Key::Backspace,
+		    // Ctrl-C, as raw mode does not send a signal
+		    0x03 => Key::Char('q'),
+		    b if b.is_ascii_graphic() || b == b' ' => Key::Char(b as char),
+		    _ => continue
+		};
+	    keys.push(key);
+	}
+	Ok(keys)
+    }
+
+    /// Replace the screen with the given lines
+    pub fn draw(&self,lines:&[String])->Result<()> {
+	let (width,height) = self.size();
+	let mut out = String::from("\x1b[H");
+	for (i,line) in lines.iter().take(height).enumerate() {
+	    if i > 0 {
+		out.push_str("\r\n");
+	    }
+	    out.extend(line.chars().take(width));
+	    out.push_str("\x1b[K");
+	}
+	out.push_str("\x1b[J");
+	let mut stdout = std::io::stdout();
+	stdout.write_all(out.as_bytes())?;
+	stdout.flush()?;
+	Ok(())
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+	print!("\x1b[?25h\x1b[?1049l");
+	let _ = std::io::stdout().flush();
+	unsafe { libc::tcsetattr(libc::STDIN_FILENO,libc::TCSANOW,&self.saved) };
+    }
+}
@@ -3,7 +3,6 @@ mod ptr;
 use ptr::*;
 
 use anyhow::{
-    anyhow,
     bail,
     Result
 };
@@ -23,7 +22,10 @@ use tokio::{
 	self,
 	Receiver,
 	Sender,
-	error::TryRecvError,
+	error::{
+	    TryRecvError,
+	    TrySendError
+	}
     }
 };
 use tokio_tungstenite::{
@@ -62,16 +64,42 @@ use std::{
     fmt::Display
 };
 
-use discipline_net::*;
+use tracing::{
+    debug,
+    error,
+    info,
+    warn
+};
+
+use discipline_net::{
+    *,
+    logging::{
+	LogFormat,
+	LogLevel,
+	LOG_USAGE
+    }
+};
 
 use pico_args::Arguments;
 
 const APP_ID : &str = "fr.exhrd.Discipline";
 
 mod config {
-    use anyhow::Result;
+    use anyhow::{
+	anyhow,
+	bail,
+	Result
+    };
     use std::path::Path;
     use serde::Deserialize;
+    use url::Url;
+    use discipline_net::{
+	Entity,
+	profile::{
+	    Profiles,
+	    NO_SIGNATURE
+	}
+    };
 
     #[derive(Debug,Clone,Deserialize)]
     pub struct Config {
@@ -79,14 +107,40 @@ mod config {
 	pub retry_delay:f64,
 	pub loop_delay:f64,
 	pub name:String,
-	pub kids:Vec<String>
+	pub kids:Vec<String>,
+	#[serde(skip,default = "Config::no_signature")]
+	pub signature:String
     }
 
     impl Config {
-	pub fn open<P:AsRef<Path>>(path:P)->Result<Self> {
-	    let fd = std::fs::File::open(path)?;
-	    let this : Self = ron::de::from_reader(fd)?;
-	    Ok(this)
+	fn no_signature()->String {
+	    NO_SIGNATURE.to_string()
+	}
+
+	/// Either the original format of the UI, or a client
+	/// configuration file with profiles
+	pub fn open<P:AsRef<Path>>(path:P,profile:Option<&str>)->Result<Self> {
+	    let path = path.as_ref();
+	    if profile.is_none() {
+		let fd = std::fs::File::open(path)?;
+		if let Ok(this) = ron::de::from_reader::<_,Self>(fd) {
+		    return Ok(this);
+		}
+	    }
+	    let profiles = Profiles::load(path).map_err(|e| anyhow!("{}",e))?;
+	    let p = profiles.get(profile).map_err(|e| anyhow!("{}",e))?;
+	    let Entity::Administrator(name) = &p.identity else {
+		bail!("The identity of the profile is not an administrator")
+	    };
+	    let url = Url::parse(&p.url)?;
+	    Ok(Self {
+		server_url:p.url.clone(),
+		retry_delay:p.retry_delay,
+		loop_delay:p.loop_delay,
+		name:name.clone(),
+		kids:p.kids.clone(),
+		signature:p.signature(&url).map_err(|e| anyhow!("{}",e))?
+	    })
 	}
     }
 }
@@ -95,6 +149,10 @@ use config::Config;
 
 const CONFIG_PATH : &str = "ui/etc/discipline.cfg";
 
+/// Pause in typing, in seconds, after which an "until" time is
+/// resolved
+const RESOLVE_DELAY : f64 = 0.3;
+
 struct BackendConnection {
     config:Config,
     recv:Receiver<Command>,
@@ -122,7 +180,12 @@ impl BackendConnection {
 		    send:sender2
 		};
 		loop {
-		    let _ = this.run().await;
+		    if let Err(e) = this.run().await {
+			warn!(url = this.config.server_url,
+			      error = %e,
+			      "Connection to server lost");
+		    }
+		    info!(delay = this.config.retry_delay,"Waiting for retry");
 		    tokio::time::sleep(std::time::Duration::from_secs_f64(
 			this.config.retry_delay)).await;
 		}
@@ -131,33 +194,85 @@ impl BackendConnection {
 	Ok((sender1,receiver2))
     }
 
+    fn message(&self,payload:Command)->Result<Message> {
+	let cmd = Envelope {
+	    sender:Entity::Administrator(self.config.name.clone()),
+	    signature:self.config.signature.clone(),
+	    payload
+	};
+	Ok(Message::Text(serde_json::to_string(&cmd)?))
+    }
+
     async fn run(&mut self)->Result<()> {
 	let url = Url::parse(&self.config.server_url)?;
 	let (mut socket,_response) = tt::connect_async(url).await?;
+	info!(url = self.config.server_url,"Connected");
+
+	// Binary messages are not handled here, so no CBOR
+	let hello = Command::Hello {
+	    version:PROTOCOL_VERSION,
+	    features:Feature::ALL.iter().copied()
+		.filter(|f| *f != Feature::Cbor)
+		.collect()
+	};
+	socket.send(self.message(hello)?).await?;
+	let features =
+	    match socket.next().await {
+		Some(Ok(Message::Text(u))) =>
+		    match decode_response(&u).map(|env| env.payload) {
+			Ok(Response::Welcome { version,features }) => {
+			    info!(version,features = ?features,"Handshake");
+			    features
+			},
+			_ => bail!("No handshake from the server, \
+				    which is probably too old")
+		    },
+		Some(msg) => bail!("Unexpected message {:?}",msg?),
+		None => bail!("Connection closed")
+	    };
+
+	if features.contains(&Feature::Events) {
+	    socket.send(self.message(Command::Subscribe)?).await?;
+	} else {
+	    warn!("The server does not support events");
+	}
+
+	// Keep the overview up to date; the first tick is immediate
+	let mut refresh = tokio::time::interval(
+	    std::time::Duration::from_secs_f64(self.config.loop_delay));
+	let status_commands : Vec<Command> =
+	    if features.contains(&Feature::AllStatus) {
+		vec![Command::GetAllStatus]
+	    } else {
+		self.config.kids.iter()
+		    .map(|kid| Command::GetStatus { subject:kid.clone() })
+		    .collect()
+	    };
 
 	loop {
 	    let _ = tokio::select! {
 		Some(payload) = self.recv.recv() => {
-		    let sender = Entity::Administrator(self.config.name.clone());
-		    let cmd = Envelope {
-			sender,
-			signature:"\\_'')_/".to_string(),
-			payload
-		    };
-		    let v = serde_json::to_string(&cmd)?;
-		    socket.send(Message::Text(v)).await?;
+		    debug!(command = payload.name(),
+			   subject = payload.subject(),
+			   "Sending");
+		    socket.send(self.message(payload)?).await?;
+		},
+		_ = refresh.tick() => {
+		    for cmd in status_commands.iter() {
+			socket.send(self.message(cmd.clone())?).await?;
+		    }
 		},
 		Some(msg) = socket.next() => {
 		    match msg? {
 			Message::Text(u) => {
-			    let resp : Result<Envelope<Response>,String> =
-				serde_json::from_str(&u)
-				.map_err(|e| anyhow!("Invalid JSON: {}",e))?;
-			    match resp {
+			    match decode_response(&u) {
 				Ok(env) => {
 				    let _ = self.send.send(env.payload).await;
 				},
-				Err(e) => bail!("Error: {}",e)
+				Err(DecodeError::Unsupported(name)) =>
+				    warn!(response = name,
+					  "Ignoring unsupported response"),
+				Err(e) => return Err(e.into())
 			    }
 			},
 			_ => bail!("Invalid message type")
@@ -168,9 +283,26 @@ impl BackendConnection {
     }
 }
 
+/// Pass a command to the backend without blocking the UI, saying so
+/// in the messages if it cannot take it
+fn send(message_buf:&TextBuffer,send_cmd:&Ptr<Sender<Command>>,cmd:Command) {
+    match send_cmd.yank_mut().try_send(cmd) {
+	Ok(()) => (),
+	Err(TrySendError::Full(_)) =>
+	    message_buf.append("Busy, please try again"),
+	Err(TrySendError::Closed(_)) =>
+	    message_buf.append("Not connected")
+    }
+}
+
 fn authorize(message_buf:TextBuffer,
 	     send_cmd:Ptr<Sender<Command>>,
 	     kid:String,t:f64) {
+    if let Err(e) = check_duration(t) {
+	message_buf.append(&e);
+	return;
+    }
+
     message_buf.append(
 	&format!("Authorize {} for {}",kid,
 		 Seconds::make(t)));
@@ -178,8 +310,39 @@ fn authorize(message_buf:TextBuffer,
     let cmd = 
 	Command::Authorize { subject:kid.clone(),
 			     duration:Some(t) };
-    send_cmd.yank_mut().blocking_send(cmd)
-	.expect("Cannot send");
+    send(&message_buf,&send_cmd,cmd);
+}
+
+fn until_time(until_h:&Entry,until_m:&Entry)->Result<WallClock,String> {
+    format!("{}:{}",until_h.text().trim(),until_m.text().trim()).parse()
+}
+
+fn status_text(status:&Status)->String {
+    let mut u =
+	if status.time_remaining > 0.0 {
+	    format!("Time remaining {}",Seconds::make(status.time_remaining))
+	} else {
+	    "No time left".to_string()
+	};
+    if let Constraint::Curfew { .. } = status.limited_by {
+	u.push_str(&format!(" ({})",status.limited_by));
+    }
+    if status.holiday {
+	u.push_str(", holiday");
+    }
+    if status.enforcement == Enforcement::Network {
+	u.push_str(", network only");
+    }
+    match status.last_ping {
+	Some(t) => u.push_str(&format!(", agent seen {} ago",Seconds::make(t))),
+	None => u.push_str(", agent never seen")
+    }
+    if status.bank != 0.0 {
+	u.push_str(&format!(", {}{} in the bank",
+			    if status.bank < 0.0 { "-" } else { "" },
+			    Seconds::make(status.bank.abs())));
+    }
+    u
 }
 
 trait TextBufferAppend {
@@ -195,37 +358,43 @@ impl TextBufferAppend for TextBuffer {
 }
     
 fn main()->glib::ExitCode {
-    let progname : String = std::env::args().nth(0).unwrap();
+    let progname : String = std::env::args().next().unwrap();
     
     let mut args = Arguments::from_env();
 
     if args.contains("-h") || args.contains("--help") {
-	eprintln!("Usage: {} [--config PATH]",
-		  progname);
+	eprintln!("Usage: {} [--config-path PATH] [--profile NAME] {}",
+		  progname,LOG_USAGE);
 	return glib::ExitCode::SUCCESS;
     }
 
     let config_path : String = args.opt_value_from_str("--config-path")
 	.expect("Cannot parse arguments")
 	.unwrap_or(CONFIG_PATH.to_string());
+    let profile : Option<String> = args.opt_value_from_str("--profile")
+	.expect("Cannot parse arguments");
+
+    let log_level : LogLevel = args.opt_value_from_str("--log-level")
+	.expect("Cannot parse arguments")
+	.unwrap_or(LogLevel::INFO);
+    let log_format : LogFormat = args.opt_value_from_str("--log-format")
+	.expect("Cannot parse arguments")
+	.unwrap_or(LogFormat::Text);
 
     let rest = args.finish();
     if !rest.is_empty() {
 	panic!("Invalid arguments: {:?}",rest);
     }
 
+    discipline_net::logging::init(log_level,log_format)
+	.expect("Cannot initialize logging");
+
     let app = Application::builder()
 	.application_id(APP_ID)
 	.build();
 
-    unsafe {
-	time::util::local_offset::set_soundness(
-	    time::util::local_offset::Soundness::Unsound
-	);
-    }
-
     app.connect_activate(move |app| {
-	let config = Config::open(&config_path)
+	let config = Config::open(&config_path,profile.as_deref())
 	    .expect("Cannot open configuration file");
 
 	let (send_cmd,receive_resp) =
@@ -245,11 +414,20 @@ fn main()->glib::ExitCode {
 	let message_buf = TextBuffer::builder()
 	    .build();
 	let box1 = Box::new(Orientation::Vertical,8);
+	let mut until_previews = Vec::new();
+	let mut status_labels = Vec::new();
 	for kid in config.kids.iter() {
 	    let frame = Frame::builder()
 		.label(kid)
 		.hexpand(true)
 		.build();
+	    let box3 = Box::new(Orientation::Vertical,4);
+	    let status_label = Label::builder()
+		.label("Status unknown")
+		.halign(gtk::Align::Start)
+		.build();
+	    box3.append(&status_label);
+	    status_labels.push((kid.clone(),status_label));
 	    let box2 = Box::new(Orientation::Horizontal,8);
 
 	    let authorize_label = Label::new(Some(" Authorize:"));
@@ -297,6 +475,56 @@ fn main()->glib::ExitCode {
 		.build();
 	    box2.append(&until_m);
 	    until_m.set_text("00");
+	    let until_preview = Label::new(None);
+	    box2.append(&until_preview);
+	    until_previews.push((kid.clone(),until_preview.clone()));
+
+	    // Resolve once typing pauses, and never block the UI on the
+	    // backend, which may be disconnected
+	    let resolve_until = {
+		let until_h = until_h.clone();
+		let until_m = until_m.clone();
+		let until_preview = until_preview.clone();
+		let send_cmd = send_cmd.refer();
+		let kid = kid.clone();
+		let pending : Ptr<Option<glib::SourceId>> = Ptr::make(None);
+		move || {
+		    if let Some(id) = pending.yank_mut().take() {
+			id.remove();
+		    }
+		    let id = glib::timeout_add_local_once(
+			std::time::Duration::from_secs_f64(RESOLVE_DELAY),
+			{
+			    let until_h = until_h.clone();
+			    let until_m = until_m.clone();
+			    let until_preview = until_preview.clone();
+			    let send_cmd = send_cmd.refer();
+			    let kid = kid.clone();
+			    let pending = pending.refer();
+			    move || {
+				pending.yank_mut().take();
+				match until_time(&until_h,&until_m) {
+				    Ok(local_time) => {
+					let cmd =
+					    Command::ResolveUntil { subject:kid,
+								    local_time };
+					if send_cmd.yank_mut().try_send(cmd).is_err() {
+					    until_preview.set_text("Not connected");
+					}
+				    },
+				    Err(e) => until_preview.set_text(&e)
+				}
+			    }
+			});
+		    *pending.yank_mut() = Some(id);
+		}
+	    };
+	    resolve_until();
+	    until_h.connect_changed({
+		let resolve_until = resolve_until.clone();
+		move |_| resolve_until()
+	    });
+	    until_m.connect_changed(move |_| resolve_until());
 
 	    let sep2 = Separator::new(Orientation::Vertical);
 	    box2.append(&sep2);
@@ -307,16 +535,17 @@ fn main()->glib::ExitCode {
 	    let get_status = Button::with_label("Get status");
 	    box2.append(&get_status);
 	    get_status.connect_clicked({
+		let message_buf = message_buf.clone();
 		let send_cmd = send_cmd.refer();
 		let kid = kid.clone();
 		move |_| {
 		    let cmd = Command::GetStatus { subject:kid.clone() };
-		    send_cmd.yank_mut().blocking_send(cmd)
-			.expect("Cannot send");
+		    send(&message_buf,&send_cmd,cmd);
 		}
 	    });
 
-	    frame.set_child(Some(&box2));
+	    box3.append(&box2);
+	    frame.set_child(Some(&box3));
 	    box1.append(&frame);
 
 	    authorize_other.connect_clicked({
@@ -350,27 +579,16 @@ fn main()->glib::ExitCode {
 		let send_cmd = send_cmd.refer();
 		let kid = kid.clone();
 		move |_| {
-		    let until_h_text = until_h.text();
-		    let until_m_text = until_m.text();
-		    if let Ok(h) = until_h_text.parse::<u8>() {
-			if let Ok(m) = until_m_text.parse::<u8>() {
-			    let time_now = OffsetDateTime::now_local()
-				.expect("Cannot get local time");
-			    let time_later =
-				time_now
-				.replace_hour(h).expect("Bad hour")
-				.replace_minute(m).expect("Bad minuet");
-			    let delta = (time_later - time_now).as_seconds_f64();
-			    
-			    authorize(message_buf.clone(),
-				      send_cmd.refer(),
-				      kid.clone(),
-				      delta);
-			} else {
-			    message_buf.append("Invalid minutes");
-			}
-		    } else {
-			message_buf.append("Invalid hours");
+		    match until_time(&until_h,&until_m) {
+			Ok(local_time) => {
+			    message_buf.append(
+				&format!("Authorize {} until {}",kid,local_time));
+			    let cmd =
+				Command::AuthorizeUntil { subject:kid.clone(),
+							  local_time };
+			    send(&message_buf,&send_cmd,cmd);
+			},
+			Err(e) => message_buf.append(&e)
 		    }
 		}
 	    });
@@ -445,6 +663,13 @@ fn main()->glib::ExitCode {
 	    {
 		let _messages_window = messages_window.clone();
 		let message_buf = message_buf.clone();
+		let show_status = move |status:&Status| {
+		    for (kid,label) in status_labels.iter() {
+			if *kid == status.subject {
+			    label.set_text(&status_text(status));
+			}
+		    }
+		};
 		move || {
 		    match receive_resp.yank_mut().try_recv() {
 			Ok(resp) => {
@@ -452,8 +677,21 @@ fn main()->glib::ExitCode {
 				Response::Authorization {
 				    subject,
 				    time_remaining,
-				    last_ping:_
+				    last_ping,
+				    bank,
+				    limited_by,
+				    holiday,
+				    enforcement
 				} => {
+				    show_status(&Status {
+					subject:subject.clone(),
+					time_remaining,
+					last_ping,
+					bank,
+					limited_by,
+					holiday,
+					enforcement
+				    });
 				    // let time_now = OffsetDateTime::now_local()
 				    // 	.expect("Cannot get local time");
 				    // let t = time_now + Duration::seconds_f64(time_remaining);
@@ -464,18 +702,65 @@ fn main()->glib::ExitCode {
 					    Seconds::make(time_remaining))
 				    );
 				},
+				Response::Welcome { .. } => (),
+				Response::Statuses(statuses) => {
+				    for status in statuses.iter() {
+					show_status(status);
+				    }
+				},
+				Response::Schedule { subject,days } => {
+				    message_buf.append(
+					&format!("Schedule of {} for {} days",
+						 subject,days.len()));
+				},
+				Response::Ledger { subject,balance,entries } => {
+				    message_buf.append(
+					&format!("Bank of {}: {} operations, \
+						  balance {}",
+						 subject,entries.len(),
+						 Seconds::make(balance)));
+				},
+				Response::Alerts { .. } => (),
+				Response::AppBudgets { subject,budgets } => {
+				    let exhausted : Vec<&str> = budgets.iter()
+					.filter(|b| b.exhausted())
+					.map(|b| b.category.name.as_str())
+					.collect();
+				    if !exhausted.is_empty() {
+					message_buf.append(
+					    &format!("{}: no time left for {}",
+						     subject,
+						     exhausted.join(", ")));
+				    }
+				},
 				Response::Ack => {
 				    message_buf.append("Server: Acknowledged");
 				},
-				Response::Error(e) => {
+				Response::Error { code,message } => {
+				    message_buf.append(
+					&format!("Server: Error {:?}: {}",
+						 code,message));
+				},
+				Response::Event(event) => {
 				    message_buf.append(
-					&format!("Server: Error {}",e));
+					&format!("Server: {}",event));
+				},
+				Response::Resolved {
+				    subject,
+				    description,
+				    ..
+				} => {
+				    for (kid,preview) in until_previews.iter() {
+					if *kid == subject {
+					    preview.set_text(&description);
+					}
+				    }
 				}
 			    }
 			},
 			Err(TryRecvError::Empty) => (),
 			Err(TryRecvError::Disconnected) => {
-			    println!("Disconnected!?");
+			    error!("Backend connection thread is gone");
 			}
 		    }
 		    true.into()
@@ -496,10 +781,7 @@ impl Seconds {
 
 impl Display for Seconds {
     fn fmt(&self,o:&mut std::fmt::Formatter<'_>)->Result<(),std::fmt::Error> {
-	let time_now = OffsetDateTime::now_local()
-	    .expect("Cannot get local time");
 	let t = self.0;
-	let t_expired = time_now + Duration::seconds_f64(t);
 	if t < 0.1 {
 	    write!(o,"zero")?;
 	} else {
@@ -526,7 +808,12 @@ impl Display for Seconds {
 		}
 	    }
 	}
-	write!(o," (until {})",t_expired)?;
+	// The local offset cannot be determined soundly once other
+	// threads are running, in which case it is left out
+	if let Ok(time_now) = OffsetDateTime::now_local() {
+	    let t_expired = time_now + Duration::seconds_f64(t);
+	    write!(o," (until {})",t_expired)?;
+	}
 	Ok(())
     }
 }